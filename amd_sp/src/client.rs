@@ -1,17 +1,21 @@
 use libafl::prelude::*;
 use libafl_bolts::prelude::*;
 use libafl_qemu::{
-    edges::{edges_map_mut_ptr, EDGES_MAP_SIZE_IN_USE, MAX_EDGES_FOUND},
-    sys::TCGTemp,
-    GuestAddr, HasInstrumentationFilter, Hook, MemAccessInfo, Qemu, QemuDrCovHelper,
-    QemuEdgeCoverageHelper, QemuExecutor, QemuExitReason, QemuHelperTuple, QemuHooks,
+    cmplog::{CmpLogObserver, QemuCmpLogHelper},
+    edges::{
+        edges_map_mut_ptr, gen_unique_edge_ids, trace_edge_hitcount, EDGES_MAP_SIZE_IN_USE,
+        MAX_EDGES_FOUND,
+    },
+    sys::{GuestUsize, TCGTemp},
+    GuestAddr, Hook, MemAccessInfo, Qemu, QemuExecutor, QemuHelperTuple, QemuHooks,
     QemuInstrumentationAddressRangeFilter, Regs,
 };
 use libasp::{
-    borrow_global_conf, get_run_conf, CustomMetadataFeedback, ExceptionFeedback, ExceptionHandler,
-    RegionWithHoles, Reset, ResetLevel, ResetState,
+    borrow_global_conf, check_left_idle, count_block, get_run_conf, injecting, psp_mutations,
+    record_block, setup_dirty_page_hooks, start_drcov, traces_block, CrashBucketFeedback,
+    CustomMetadataFeedback, EitherScheduler, ExceptionFeedback, ExceptionHandler, HangFeedback,
+    MutatorConfig, PspLayout, RegionWithHoles, ResetFailureFeedback, ResetLevel, SchedulerConfig,
 };
-use rangemap::RangeMap;
 use std::fmt::Debug;
 use std::ops::Range;
 use std::{
@@ -27,15 +31,21 @@ use std::{
 
 use crate::harness;
 
-type MyState =
-    StdState<BytesInput, InMemoryCorpus<BytesInput>, RomuDuoJrRand, CachedOnDiskCorpus<BytesInput>>;
+pub type MyState = StdState<
+    BytesInput,
+    CachedOnDiskCorpus<BytesInput>,
+    RomuDuoJrRand,
+    CachedOnDiskCorpus<BytesInput>,
+>;
 
 pub fn run_client<SP>(
     qemu_args: Vec<String>,
     state: Option<MyState>,
     solutions_dir: PathBuf,
+    queue_dir: PathBuf,
     log_dir: PathBuf,
     input_dir: PathBuf,
+    queue_files: Vec<PathBuf>,
     mut mgr: /*SimpleEventManager<SimpleMonitor<SP>,MyState>*/ LlmpRestartingEventManager<
         (),
         MyState,
@@ -47,7 +57,9 @@ where
     //SP: FnMut(&str)
 {
     let conf = &get_run_conf().unwrap().yaml_config;
+    let fuzzer_conf = &conf.fuzzer;
     let env: Vec<(String, String)> = env::vars().collect();
+    log::info!("Fuzzer config: {:?}", fuzzer_conf);
 
     let emu = Qemu::init(&qemu_args, &env).unwrap();
 
@@ -59,29 +71,50 @@ where
             addr_of_mut!(MAX_EDGES_FOUND),
         ))
         .track_indices()
+        .track_novelties()
     };
 
     // Create an observation channel to keep track of the execution time
     let time_observer = TimeObserver::new("time");
+    // New maximization map feedback linked to the edges observer and the feedback state
+    let map_feedback = MaxMapFeedback::new(&edges_observer);
+    // Measures exec times and map stability, needed by the power schedules
+    let calibration = CalibrationStage::new(&map_feedback);
+    // Generalizes new test-cases for the Grimoire mutators
+    let generalization = GeneralizationStage::new(&edges_observer);
+
     // Feedback to rate the interestingness of an input
-    // This one is composed by two Feedbacks in OR
-    let mut feedback = feedback_or!(
-        // New maximization map feedback linked to the edges observer and the feedback state
-        MaxMapFeedback::new(&edges_observer),
-        // Time feedback, this one does not need a feedback state
-        TimeFeedback::new(&time_observer)
+    // This one is composed by two Feedbacks in OR, a test-case that did not run is skipped
+    let mut feedback = feedback_and_fast!(
+        feedback_not!(ResetFailureFeedback::new()),
+        feedback_or!(
+            map_feedback,
+            // Time feedback, this one does not need a feedback state
+            TimeFeedback::new(&time_observer)
+        )
     );
 
-    let objective_coverage_feedback =
-        MaxMapFeedback::with_name("objective_coverage_feedback", &edges_observer);
+    // Deduplicate objectives by their crash site and call stack
+    let crash_bucket_feedback = CrashBucketFeedback::new(
+        emu,
+        get_run_conf().unwrap().run_dir.join("buckets"),
+        conf.crashes.buckets.max_solutions,
+        conf.crashes.buckets.stack_depth,
+    );
 
-    // A feedback to choose if an input is a solution or not
+    // A feedback to choose if an input is a solution or not.
+    // Hangs are a separate class of solutions, stored in `hangs/`
+    // A test-case that did not run because of a failed reset is no solution either
     let mut objective = feedback_and_fast!(
-        feedback_and_fast!(
-            feedback_or!(CrashFeedback::new(), ExceptionFeedback::new()),
-            objective_coverage_feedback
+        feedback_not!(ResetFailureFeedback::new()),
+        feedback_or_fast!(
+            feedback_and_fast!(
+                feedback_or!(CrashFeedback::new(), ExceptionFeedback::new()),
+                crash_bucket_feedback
+            ),
+            HangFeedback::new(get_run_conf().unwrap().run_dir.join("hangs"))
         ),
-        CustomMetadataFeedback::new(emu) // always true, used to write metadata output whenever a test-case is a solution
+        CustomMetadataFeedback::new(emu) // true if the test-case ran, used to write metadata output whenever a test-case is a solution
     );
 
     // create a State from scratch
@@ -91,8 +124,9 @@ where
         StdState::new(
             // RNG
             StdRand::with_seed(current_nanos()),
-            // Corpus that will be evolved, we keep it in memory for performance
-            InMemoryCorpus::new(),
+            // Corpus that will be evolved, on disk so a campaign can be resumed,
+            // with the most recently used test-cases cached in memory
+            CachedOnDiskCorpus::new(queue_dir, 4096).unwrap(),
             // Corpus in which we store solutions,
             // on disk so the user can get them after stopping the fuzzer
             CachedOnDiskCorpus::new(cloned_solutions_dir, 100).unwrap(),
@@ -106,57 +140,46 @@ where
     });
 
     // Maximum input length
-    state.set_max_size(conf.input.total_size());
+    state.set_max_size(
+        conf.mailbox
+            .as_ref()
+            .map_or(conf.input.total_size(), |mailbox| mailbox.max_input_size()),
+    );
+
+    // Dictionary for the token mutations
+    if let Some(tokens_file) = &fuzzer_conf.tokens {
+        if !state.has_metadata::<Tokens>() {
+            state.add_metadata(Tokens::from_file(tokens_file)?);
+        }
+    }
 
-    // TODO: There is a better scheduling policy??
-    // A minimization+queue policy to get testcasess from the corpus
-    let scheduler = IndexesLenTimeMinimizerScheduler::new(&edges_observer, QueueScheduler::new());
+    // A minimization policy over the configured queue or power schedule
+    let scheduler = IndexesLenTimeMinimizerScheduler::new(
+        &edges_observer,
+        match fuzzer_conf.scheduler {
+            SchedulerConfig::Queue => EitherScheduler::Left(QueueScheduler::new()),
+            schedule => EitherScheduler::Right(StdWeightedScheduler::with_schedule(
+                &mut state,
+                &edges_observer,
+                Some(power_schedule(schedule)),
+            )),
+        },
+    );
 
     // A fuzzer with feedbacks and a corpus scheduler
     let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
 
-    let mut hooks = setup_hooks(log_dir, emu, conf);
+    let mut hooks = setup_hooks(emu, conf, fuzzer_conf.stages.tracing);
 
-    // Set fuzzing sinks
-    for sink in &conf.harness.sinks {
-        emu.set_breakpoint(*sink);
-    }
-    // Configure ResetState and ExceptionHandler helpers
-    let mut rs = ResetState::new(conf.flash.size);
-    let mut eh = ExceptionHandler::new();
-
-    // Go to FUZZ_START
-    let addr = conf.harness.start;
-    emu.set_breakpoint(addr);
-    unsafe {
-        match emu.run() {
-            Ok(QemuExitReason::Breakpoint(guest_addr)) => {
-                assert_eq!(guest_addr, conf.harness.start);
-                println!("Guest addr: {guest_addr:#x}, Conf harness: {addr:#x}")
-            }
-            _ => panic!("Unexpected QEMU exit."),
-        }
-    };
-    emu.remove_breakpoint(conf.harness.start);
-    hooks
-        .match_helper_mut::<QemuDrCovHelper>()
-        .unwrap()
-        .update_filter(QemuInstrumentationAddressRangeFilter::None, &emu);
-    let cpu = emu.current_cpu().unwrap(); // ctx switch safe
-    let pc: u64 = cpu.read_reg(Regs::Pc).unwrap();
-    log::debug!("#### First exit at {:#x} ####", pc);
-    // Save emulator state
-    rs.save(&emu, &ResetLevel::RustSnapshot);
-    // Catching exceptions
-    eh.start(&emu);
-    // Setup crash breakpoints
-    for bp in &conf.crashes.breakpoints {
-        emu.set_breakpoint(*bp);
-    }
+    // Run to the harness start and save the state there
+    let (rs, eh) = harness::prepare(emu);
+    // The DrCov trace starts at the harness start, the blocks translated so far are translated again
+    start_drcov(log_dir.join("drcov.log"));
+    emu.flush_jit();
 
     // The closure that we want to fuzz
-    let mut harness = harness::create_harness(rs, emu);
-    let timeout = Duration::new(15, 0); // 5sec
+    let mut harness = harness::create_harness(rs, eh, emu);
+    let timeout = Duration::from_millis(conf.harness.timeout_ms);
     let mut executor = QemuExecutor::new(
         &mut hooks,
         &mut harness,
@@ -168,10 +191,32 @@ where
     )
     .expect("Failed to create QemuExecutor");
 
-    // Instead of calling the timeout handler and restart the process, trigger a breakpoint ASAP
+    // Instead of calling the timeout handler and restart the process, trigger a breakpoint ASAP.
+    // The harness reports the execution as a hang.
     executor.break_on_timeout();
 
-    if state.must_load_initial_inputs() {
+    // Collects the comparisons for the tracing stage
+    let cmplog_observer = CmpLogObserver::new("cmplog", true);
+    let mut executor = ShadowExecutor::new(executor, tuple_list!(cmplog_observer));
+
+    if state.must_load_initial_inputs() && !queue_files.is_empty() {
+        // A resumed campaign keeps its whole queue, interesting or not
+        state
+            .load_initial_inputs_by_filenames_forced(
+                &mut fuzzer,
+                &mut executor,
+                &mut mgr,
+                &queue_files,
+            )
+            .unwrap_or_else(|_| {
+                log::error!("Failed to reload the queue of the previous run");
+                std::process::exit(0);
+            });
+        log::info!(
+            "We reloaded {} inputs from the previous queue.",
+            state.corpus().count()
+        );
+    } else if state.must_load_initial_inputs() {
         state
             .load_initial_inputs(&mut fuzzer, &mut executor, &mut mgr, &[input_dir.clone()])
             .unwrap_or_else(|_| {
@@ -185,9 +230,77 @@ where
         );
     }
 
-    // Setup a mutational stage with a basic bytes mutator
-    let mutator = StdScheduledMutator::new(havoc_mutations());
-    let mut stages = tuple_list!(StdMutationalStage::new(mutator));
+    // Bytes mutations, tokens from the dictionary and the PSP directory mutations.
+    // Mutations without a dictionary or directories in the input are skipped.
+    let layout = if fuzzer_conf.psp_mutations {
+        PspLayout::new(&conf.input, conf.flash.size)
+    } else {
+        PspLayout {
+            regions: Vec::new(),
+            flash_size: conf.flash.size,
+        }
+    };
+    let mutations = || {
+        havoc_mutations()
+            .merge(tokens_mutations())
+            .merge(psp_mutations(layout.clone(), fuzzer_conf.psp_fix_count))
+    };
+    let mopt = fuzzer_conf.mutator == MutatorConfig::MOpt;
+    let power = fuzzer_conf.scheduler.is_power_schedule();
+    if power && !fuzzer_conf.stages.calibration {
+        log::warn!("Power schedules need the calibration stage, enabling it");
+    }
+
+    // Stages from the `fuzzer` config
+    let tracing = ShadowTracingStage::new(&mut executor);
+    let i2s = StdMutationalStage::new(StdScheduledMutator::new(tuple_list!(I2SRandReplace::new())));
+    let grimoire = StdMutationalStage::transforming(StdScheduledMutator::new(tuple_list!(
+        GrimoireExtensionMutator::new(),
+        GrimoireRecursiveReplacementMutator::new(),
+        GrimoireStringReplacementMutator::new(),
+        GrimoireRandomDeleteMutator::new(),
+    )));
+    let mut stages = tuple_list!(
+        IfStage::new(enabled(fuzzer_conf.calibration()), tuple_list!(calibration)),
+        IfStage::new(
+            enabled(fuzzer_conf.stages.tracing),
+            tuple_list!(tracing, i2s)
+        ),
+        IfStage::new(
+            enabled(fuzzer_conf.stages.generalization),
+            tuple_list!(generalization, grimoire)
+        ),
+        IfStage::new(
+            enabled(!mopt && !power),
+            tuple_list!(StdMutationalStage::new(StdScheduledMutator::new(
+                mutations()
+            )))
+        ),
+        IfStage::new(
+            enabled(!mopt && power),
+            tuple_list!(StdPowerMutationalStage::new(StdScheduledMutator::new(
+                mutations()
+            )))
+        ),
+        IfStage::new(
+            enabled(mopt && !power),
+            tuple_list!(StdMutationalStage::new(StdMOptMutator::new(
+                &mut state,
+                mutations(),
+                7,
+                5
+            )?))
+        ),
+        IfStage::new(
+            enabled(mopt && power),
+            tuple_list!(StdPowerMutationalStage::new(StdMOptMutator::new(
+                &mut state,
+                mutations(),
+                7,
+                5
+            )?))
+        ),
+    );
 
     log::info!("Starting fuzzing loop");
     fuzzer.fuzz_loop(&mut stages, &mut executor, &mut state, &mut mgr)?;
@@ -196,40 +309,126 @@ where
     Ok(())
 }
 
+/// Condition of an [`IfStage`] fixed by the config
+fn enabled<Z, E, S, EM>(
+    on: bool,
+) -> impl FnMut(&mut Z, &mut E, &mut S, &mut EM) -> Result<bool, Error> {
+    move |_fuzzer, _executor, _state, _mgr| Ok(on)
+}
+
+fn power_schedule(scheduler: SchedulerConfig) -> PowerSchedule {
+    match scheduler {
+        SchedulerConfig::Explore | SchedulerConfig::Queue => PowerSchedule::EXPLORE,
+        SchedulerConfig::Exploit => PowerSchedule::EXPLOIT,
+        SchedulerConfig::Fast => PowerSchedule::FAST,
+        SchedulerConfig::Coe => PowerSchedule::COE,
+        SchedulerConfig::Lin => PowerSchedule::LIN,
+        SchedulerConfig::Quad => PowerSchedule::QUAD,
+    }
+}
+
 fn setup_hooks(
-    log_dir: PathBuf,
     emu: Qemu,
     conf: &libasp::YAMLConfig,
+    tracing: bool,
 ) -> Box<QemuHooks<impl QemuHelperTuple<MyState> + Debug, MyState>> {
-    // Configure DrCov helper
-    let mut log_drcov_path = log_dir.clone();
-    log_drcov_path.push("drcov.log");
-    let mut rangemap = RangeMap::<usize, (u16, String)>::new();
-
-    // TODO: Should this be dynamic?
-    rangemap.insert(
-        0x0_usize..0xffff_9000_usize,
-        (0, "on-chip-ryzen-zen.bl".to_string()),
-    );
-    let filter = QemuInstrumentationAddressRangeFilter::DenyList(vec![Range {
-        start: 0x0_u32,
-        end: 0xffff_ffff_u32,
-    }]);
+    // Comparisons are only logged for the tracing stage
+    let cmplog_filter = if tracing {
+        QemuInstrumentationAddressRangeFilter::None
+    } else {
+        QemuInstrumentationAddressRangeFilter::DenyList(vec![Range {
+            start: 0x0_u32,
+            end: 0xffff_ffff_u32,
+        }])
+    };
     // Configure QEMU hook helper
-    let hooks = QemuHooks::new(
-        emu,
-        tuple_list!(
-            QemuEdgeCoverageHelper::default(),
-            QemuDrCovHelper::new(filter, rangemap, log_drcov_path, false,),
-        ),
+    let hooks = QemuHooks::new(emu, tuple_list!(QemuCmpLogHelper::new(cmplog_filter)));
+    // Edge coverage and the DrCov trace, without the instructions injected by the harness
+    hooks.edges(
+        Hook::Function(gen_edge_hook),
+        Hook::Raw(trace_edge_hitcount),
     );
-    conf.tunnels.setup(&hooks);
+    hooks.blocks(
+        Hook::Function(gen_drcov_block_hook),
+        Hook::Function(post_gen_drcov_block_hook),
+        Hook::Empty,
+    );
+    setup_crash_hooks(&hooks, conf);
+    hooks
+}
+
+fn gen_edge_hook<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    state: Option<&mut S>,
+    src: GuestAddr,
+    dest: GuestAddr,
+) -> Option<u64>
+where
+    S: HasMetadata + UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    if injecting() {
+        return None;
+    }
+    gen_unique_edge_ids(hooks, state, src, dest)
+}
+
+fn gen_drcov_block_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _id: Option<&mut S>,
+    pc: GuestAddr,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    traces_block(pc).then_some(0)
+}
+fn post_gen_drcov_block_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _id: Option<&mut S>,
+    pc: GuestAddr,
+    block_length: GuestUsize,
+) where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    record_block(pc, u64::from(block_length));
+}
+
+/// Installs the tunnels and the block/write hooks used for crash detection
+pub fn setup_crash_hooks<QT, S>(hooks: &QemuHooks<QT, S>, conf: &libasp::YAMLConfig)
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    conf.tunnels.setup(hooks);
     // Block hooks and write hooks for crash detection
     hooks.blocks(
         Hook::Function(gen_block_hook),
         Hook::Empty,
         Hook::Function(exec_block_hook),
     );
+    // Every executed block counts against the budget
+    if conf.harness.max_blocks.is_some() {
+        hooks.blocks(
+            Hook::Function(gen_budget_block_hook),
+            Hook::Empty,
+            Hook::Function(exec_budget_block_hook),
+        );
+    }
+    // Stops once the workloop left the mailbox idle loop with a new command
+    if conf.mailbox.is_some() {
+        hooks.blocks(
+            Hook::Function(gen_mailbox_block_hook),
+            Hook::Empty,
+            Hook::Function(exec_mailbox_block_hook),
+        );
+    }
+    // Dirty SRAM pages for the DirtyPages reset level
+    if conf.snapshot.uses(ResetLevel::DirtyPages) {
+        setup_dirty_page_hooks(hooks);
+    }
     if !conf.crashes.mmap.no_write_hooks.is_empty() {
         log::debug!("Adding write generation hooks");
         hooks.writes(
@@ -243,7 +442,6 @@ fn setup_hooks(
     } else {
         log::debug!("No write generation hooks");
     }
-    hooks
 }
 
 static COUNTER_WRITE_HOOKS: AtomicU64 = AtomicU64::new(0);
@@ -260,9 +458,15 @@ where
     S: UsesInput,
     QT: QemuHelperTuple<S>,
 {
+    // Injected instructions are not part of the guest, their `MCR`s are not watched either
+    if injecting() {
+        return None;
+    }
     let state: Option<&mut FlashHookConfig> = hooks.match_helper_mut();
     let id = COUNTER_EDGE_HOOKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
     let conf = borrow_global_conf().unwrap();
+    // Stop when the guest moves its vectors, so the harness can move the exception hooks
+    ExceptionHandler::watch_vector_writes(&hooks.qemu(), src);
     for no_exec in conf.crashes.mmap.no_exec.iter() {
         if src >= no_exec.begin && src < no_exec.end {
             log::debug!("Generate block:");
@@ -285,6 +489,9 @@ where
     S: UsesInput,
     QT: QemuHelperTuple<S>,
 {
+    if injecting() {
+        return;
+    }
     let emu = hooks.qemu();
     if FLASH_READ_HOOK_ID.get().unwrap() == &id {
         let conf = borrow_global_conf().unwrap();
@@ -332,6 +539,62 @@ where
     emu.current_cpu().unwrap().trigger_breakpoint();
 }
 
+fn gen_budget_block_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _id: Option<&mut S>,
+    _src: GuestAddr,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    Some(0)
+}
+fn exec_budget_block_hook<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    _data_maybe: Option<&mut S>,
+    _id: u64,
+) where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    if injecting() {
+        return;
+    }
+    let max_blocks = borrow_global_conf().unwrap().harness.max_blocks.unwrap();
+    if count_block(max_blocks) {
+        hooks.qemu().current_cpu().unwrap().trigger_breakpoint();
+    }
+}
+
+fn gen_mailbox_block_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _id: Option<&mut S>,
+    _src: GuestAddr,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    Some(0)
+}
+fn exec_mailbox_block_hook<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    _data_maybe: Option<&mut S>,
+    _id: u64,
+) where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    if injecting() {
+        return;
+    }
+    let mailbox = borrow_global_conf().unwrap().mailbox.as_ref().unwrap();
+    if check_left_idle(&hooks.qemu(), mailbox) {
+        hooks.qemu().current_cpu().unwrap().trigger_breakpoint();
+    }
+}
+
 fn gen_writes_hook<QT, S>(
     _hooks: &mut QemuHooks<QT, S>,
     _state: Option<&mut S>,
@@ -343,6 +606,9 @@ where
     S: UsesInput,
     QT: QemuHelperTuple<S>,
 {
+    if injecting() {
+        return None;
+    }
     // TODO: for known write locations at "compile" time
     // Don't emit hooks if they are outside of range
     let conf = borrow_global_conf().unwrap();
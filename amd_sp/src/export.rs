@@ -0,0 +1,48 @@
+use libasp::{build_flash_image, get_run_conf, parse_commands};
+
+use std::{fs, path::Path};
+
+/// Writes a full flash image for every solution of the run, using the same
+/// input mapping as the harness and fixing the directory checksums.
+/// Solutions of the mailbox mode are written as a listing of their commands.
+pub fn export(out_dir: &Path) {
+    let run_conf = get_run_conf().unwrap();
+    let conf = &run_conf.yaml_config;
+    let base = fs::read(&conf.flash.base).unwrap();
+    let solutions_dir = run_conf.run_dir.join("solutions");
+    fs::create_dir_all(out_dir).unwrap();
+
+    let mut counter = 0;
+    for entry in fs::read_dir(&solutions_dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // Skip metadata and lock files
+        if !path.is_file() || name.starts_with('.') {
+            continue;
+        }
+        let input = fs::read(&path).unwrap();
+        if let Some(mailbox) = &conf.mailbox {
+            let listing: String = parse_commands(&input, mailbox)
+                .iter()
+                .map(|command| format!("{command}\n"))
+                .collect();
+            let listing_path = out_dir.join(format!("{name}_commands.txt"));
+            fs::write(&listing_path, listing).unwrap();
+            println!("{}\t---> {}", path.display(), listing_path.display());
+            counter += 1;
+            continue;
+        }
+        let image = match build_flash_image(&base, &conf.input, &input) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let image_path = out_dir.join(format!("{name}_full.ROM"));
+        fs::write(&image_path, image).unwrap();
+        println!("{}\t---> {}", path.display(), image_path.display());
+        counter += 1;
+    }
+    println!("\n{} solution files have been transformed", counter);
+}
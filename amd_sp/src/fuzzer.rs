@@ -1,5 +1,4 @@
 use libafl::prelude::*;
-use libafl_bolts::core_affinity::Cores;
 use libafl_bolts::current_time;
 use libafl_bolts::shmem::ShMemProvider;
 use libafl_bolts::shmem::StdShMemProvider;
@@ -15,17 +14,55 @@ use std::io;
 use std::io::Write;
 use std::os::fd::FromRawFd;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::client;
-use crate::setup::parse_args;
+use crate::export;
+use crate::replay;
+use crate::setup::{parse_args, LaunchConfig, Mode};
+
+/// Test-cases in the queue of a previous run, without the hidden lock and metadata files
+fn previous_queue(queue_dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(queue_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.is_file() && !path.file_name().unwrap().to_string_lossy().starts_with('.')
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Removes the lock files the corpus left in `dir`. The previous run is gone and a
+/// reloaded test-case is then written to its old file instead of a `-2` copy.
+fn release_corpus_locks(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "lafl_lock") {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+/// Moves a log that would be overwritten to the next free `{log}.{n}`
+fn keep_log(path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let mut n = 1;
+    while path.with_extension(format!("log.{n}")).exists() {
+        n += 1;
+    }
+    fs::rename(path, path.with_extension(format!("log.{n}"))).unwrap();
+}
 
-fn run(qemu_args: Vec<String>) {
+fn run(qemu_args: Vec<String>, resume: bool, launch: LaunchConfig) {
     let conf = get_run_conf().unwrap();
 
-    // Create directory for this run
+    // Create directory for this run, a resumed run keeps its directory
     let run_dir = &conf.run_dir;
-    if env::var("AFL_LAUNCHER_CLIENT").is_err() && run_dir.exists() {
+    if env::var("AFL_LAUNCHER_CLIENT").is_err() && run_dir.exists() && !resume {
         fs::remove_dir_all(run_dir).unwrap();
     }
     fs::create_dir_all(run_dir).unwrap();
@@ -38,31 +75,63 @@ fn run(qemu_args: Vec<String>) {
     let mut solutions_dir = run_dir.clone();
     solutions_dir.push("solutions");
     fs::create_dir_all(&solutions_dir).unwrap();
+    let mut queue_dir = run_dir.clone();
+    queue_dir.push("queue");
+    fs::create_dir_all(&queue_dir).unwrap();
     let mut config_path = run_dir.clone();
     config_path.push("config.yaml");
     if env::var("AFL_LAUNCHER_CLIENT").is_err() {
-        fs::copy(&conf.config_path, &config_path).unwrap();
+        if resume {
+            release_corpus_locks(&queue_dir);
+            // libafl.log is appended to, drcov.log is merged back once the run ends
+            set_aside_drcov(&log_dir.join("drcov.log"));
+            // The launcher rewrites its files
+            for log in ["stdout.log", "env_logger.log"] {
+                keep_log(&log_dir.join(log));
+            }
+        } else {
+            conf.write_config(&config_path);
+        }
     }
 
     let conf = &conf.yaml_config;
 
     println!("{:#X?}", conf);
     // Generate initial inputs
-    let input_dir: PathBuf = InitialInput::new().create_initial_inputs(
-        &conf.input.initial,
-        &conf.input.mem,
-        conf.flash.size,
-        conf.input.total_size(),
-        input_dir,
-    );
+    let input_dir: PathBuf = match &conf.mailbox {
+        Some(mailbox) => InitialInput::new().create_mailbox_inputs(mailbox, input_dir),
+        None => InitialInput::new().create_initial_inputs(
+            &conf.input.initial,
+            &conf.input.mem,
+            conf.flash.size,
+            conf.input.total_size(),
+            input_dir,
+        ),
+    };
+
+    // A resumed run starts with the previous queue, the solutions stay in their directory
+    // and the persisted crash buckets keep them from being stored again
+    let queue_files = if resume {
+        previous_queue(&queue_dir)
+    } else {
+        Vec::new()
+    };
+    if resume && env::var("AFL_LAUNCHER_CLIENT").is_err() {
+        println!(
+            "Resuming with {} test-cases from the previous run",
+            queue_files.len()
+        );
+    }
 
     let mut run_client = |state: Option<_>, mgr, _core_id| -> Result<(), Error> {
         client::run_client(
             qemu_args.clone(),
             state,
             solutions_dir.clone(),
+            queue_dir.clone(),
             log_dir.clone(),
             input_dir.clone(),
+            queue_files.clone(),
             mgr,
         )
     };
@@ -102,19 +171,21 @@ fn run(qemu_args: Vec<String>) {
         // The shared memory allocator
         let shmem_provider = StdShMemProvider::new().expect("Failed to init shared memory");
 
-        // Launcher config
-        let broker_port = 1337;
-        let num_cores = get_run_conf().unwrap().num_cores;
-        let cores = Cores::from_cmdline(&format!("0-{num_cores}")).unwrap();
-
-        // Build and run a Launcher
+        // Build and run a Launcher. With a remote broker, the local broker forwards
+        // new test-cases and objectives to it and receives the ones of the other hosts.
+        // Broker-to-broker is enough to share them, the centralized manager would
+        // run every shared test-case again on a main client before forwarding it.
+        if let Some(remote_broker) = launch.remote_broker {
+            println!("Connecting to the broker at {remote_broker}");
+        }
         match Launcher::builder()
             .shmem_provider(shmem_provider)
-            .broker_port(broker_port)
+            .broker_port(launch.broker_port)
+            .remote_broker_addr(launch.remote_broker)
             .configuration(EventConfig::from_build_id())
             .monitor(monitor)
             .run_client(&mut run_client)
-            .cores(&cores)
+            .cores(&launch.cores)
             .stdout_file(Some(std_out_path.to_str().unwrap()))
             .stderr_file(Some(std_err_path.to_str().unwrap()))
             .build()
@@ -124,6 +195,7 @@ fn run(qemu_args: Vec<String>) {
             Err(Error::ShuttingDown) => println!("Fuzzing stopped by user. Good bye."),
             Err(err) => panic!("Failed to run launcher: {:#?}", err),
         }
+        merge_back_drcov(&log_dir.join("drcov.log"));
     }
 
     // {
@@ -144,6 +216,10 @@ fn run(qemu_args: Vec<String>) {
 pub fn fuzz() {
     env_logger::init();
     // Generate QEMU start arguments
-    let qemu_args = parse_args();
-    run(qemu_args)
+    let (mode, qemu_args) = parse_args();
+    match mode {
+        Mode::Fuzz { resume, launch } => run(qemu_args, resume, launch),
+        Mode::Replay { testcase } => replay::replay(qemu_args, &testcase),
+        Mode::Export { out_dir } => export::export(&out_dir),
+    }
 }
@@ -1,9 +1,14 @@
-use std::process;
+use std::{fs, process, time::Instant};
 
 use libafl::prelude::*;
 use libafl_bolts::{os::unix_signals::Signal, prelude::*};
 use libafl_qemu::{GuestAddr, Qemu, QemuExitError, QemuExitReason, QemuShutdownCause, Regs};
-use libasp::{get_run_conf, ExceptionHandler, FixedConfig, Reset, ResetState};
+use libasp::{
+    block_budget_exhausted, clear_triggered_exception, deadline_passed, fix_checksums, flush_drcov,
+    get_run_conf, input_from_image, mailbox_response, parse_commands, reset_idle, send_command,
+    send_from_idle, set_last_exit, start_block_budget, take_left_idle, track_dirty_pages,
+    ExceptionHandler, ExitInfo, ExitReason, InputConfig, Reset, ResetLevel, ResetState,
+};
 
 extern "C" {
     fn aspfuzz_write_smn_flash(addr: GuestAddr, len: i32, buf: *mut u8);
@@ -12,97 +17,374 @@ pub unsafe fn write_flash_mem(addr: GuestAddr, buf: &[u8]) {
     aspfuzz_write_smn_flash(addr, buf.len() as i32, buf.as_ptr() as *mut u8);
 }
 
+/// Runs the emulator until `harness.start` (or the `snapshot.start_point`), saves the
+/// state there and arms the exception handler and the crash breakpoints.
+/// With `snapshot.persist` the state is stored in the run directory and
+/// loaded from there instead of booting again.
+pub fn prepare(emu: Qemu) -> (ResetState, ExceptionHandler) {
+    let run_conf = get_run_conf().unwrap();
+    let conf = &run_conf.yaml_config;
+    let snapshot_dir = run_conf.run_dir.join("start_snapshot");
+    let start_point = conf.snapshot.start_point();
+    let fuzz_start = start_point.map_or(conf.harness.start, |point| point.addr);
+
+    // Set fuzzing sinks
+    for sink in &conf.harness.sinks {
+        emu.set_breakpoint(*sink);
+    }
+    let mut eh = ExceptionHandler::new();
+    // The tracking is only read by the DirtyPages reset level and limits the SRAM size
+    if conf.snapshot.uses(ResetLevel::DirtyPages) {
+        track_dirty_pages(&conf.memory);
+    }
+
+    let stored = if conf.snapshot.persist {
+        match ResetState::from_dir(&snapshot_dir, &conf.memory) {
+            Ok(rs) if rs.pc() == Some(fuzz_start) => Some(rs),
+            Ok(rs) => {
+                log::warn!(
+                    "Stored snapshot {} is not at {:#x}, booting",
+                    rs.name(),
+                    fuzz_start
+                );
+                None
+            }
+            Err(e) => {
+                log::info!("No stored snapshot ({e}), booting");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let restored = stored.and_then(|mut rs| {
+        // QEMU only has a current CPU once it ran
+        run_until(emu, emu.cpu_from_index(0).read_reg(Regs::Pc).unwrap());
+        match rs.restore(&emu) {
+            Ok(()) => {
+                log::info!("Loaded the start snapshot from {}", snapshot_dir.display());
+                Some(rs)
+            }
+            Err(e) => {
+                log::warn!("Refusing the stored snapshot ({e}), booting");
+                rs.power_on(&emu).unwrap();
+                None
+            }
+        }
+    });
+    let rs = match restored {
+        Some(rs) => rs,
+        None => {
+            // Go to FUZZ_START
+            run_until(emu, conf.harness.start);
+            let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+            let pc: u64 = cpu.read_reg(Regs::Pc).unwrap();
+            log::debug!("#### First exit at {:#x} ####", pc);
+            let mut rs = ResetState::new(&conf.memory);
+            if let Some(point) = start_point {
+                // Continue to the point with the flash of the base image
+                let flash_base = fs::read(&conf.flash.base).unwrap();
+                let base_input = input_from_image(&flash_base, &conf.input);
+                write_input(&conf.input, &flash_base, &base_input);
+                run_until(emu, point.addr);
+                log::debug!(
+                    "#### Snapshot point {} at {:#x} ####",
+                    point.name,
+                    point.addr
+                );
+                rs.save_named(&emu, &point.name).unwrap();
+            } else {
+                // Save emulator state
+                rs.save(&emu, &ResetLevel::RustSnapshot).unwrap();
+            }
+            if conf.snapshot.persist {
+                rs.store(&emu, &snapshot_dir).unwrap();
+            }
+            rs
+        }
+    };
+    // Catching exceptions
+    eh.start(&emu);
+    // Setup crash breakpoints
+    for bp in &conf.crashes.breakpoints {
+        emu.set_breakpoint(*bp);
+    }
+    // The workloop waiting for the next mailbox command
+    if let Some(mailbox) = &conf.mailbox {
+        emu.set_breakpoint(mailbox.idle);
+    }
+    (rs, eh)
+}
+
+/// Runs the emulator until it reaches `addr`
+fn run_until(emu: Qemu, addr: GuestAddr) {
+    emu.set_breakpoint(addr);
+    unsafe {
+        match emu.run() {
+            Ok(QemuExitReason::Breakpoint(guest_addr)) => {
+                assert_eq!(
+                    guest_addr, addr,
+                    "Stopped at {guest_addr:#x} before reaching {addr:#x}"
+                );
+                println!("Guest addr: {guest_addr:#x}")
+            }
+            _ => panic!("Unexpected QEMU exit."),
+        }
+    };
+    emu.remove_breakpoint(addr);
+}
+
 pub fn create_harness(
     mut rs: ResetState,
+    mut eh: ExceptionHandler,
     emu: Qemu,
 ) -> impl FnMut(&BytesInput) -> ExitKind + Clone {
+    // The flash contents outside of the input, to recompute the checksums
+    let flash_base = fs::read(&get_run_conf().unwrap().yaml_config.flash.base).unwrap();
+    let base_input = input_from_image(&flash_base, &get_run_conf().unwrap().yaml_config.input);
     // These variables are captured in the closure and persist across reruns
     let mut is_crash_snapshot = false;
     let mut counter_snapshot = 0;
+    let mut counter_check = 0;
+    let mut previous_input: Vec<u8> = Vec::new();
     move |input| {
         let conf = &get_run_conf().unwrap().yaml_config;
         log::debug!("### Start harness");
+        // Blocks translated by the last execution
+        flush_drcov();
+        // The budget and the deadline of the last execution would stop the
+        // instructions the reset and `rearm` inject
+        start_block_budget();
 
         // Reset emulator state
-        if is_crash_snapshot {
+        let reset_level = if is_crash_snapshot {
             is_crash_snapshot = false;
-            rs.load(&emu, &conf.snapshot.on_crash);
+            conf.snapshot.on_crash
         } else if counter_snapshot >= conf.snapshot.period {
             counter_snapshot = 0;
-            rs.load(&emu, &conf.snapshot.periodically);
+            conf.snapshot.periodically
         } else {
-            rs.load(&emu, &conf.snapshot.default);
+            conf.snapshot.default
+        };
+        // A hard reset runs to the start point again, with the flash of the base image
+        if reset_level == ResetLevel::HardReset && conf.snapshot.start_point().is_some() {
+            write_input(&conf.input, &flash_base, &base_input);
         }
-
-        #[cfg(feature = "debug")]
-        print_input(input.bytes());
-
-        // Input to memory
-        let target = input.target_bytes();
-        let mut target_buf = target.as_slice();
-        if target_buf.len() > conf.input.total_size() {
-            target_buf = &target_buf[..conf.input.total_size()];
+        let reset = rs.load_rearming(&emu, &reset_level, &mut eh);
+        // Only exceptions of this test-case end up in its metadata, not the ones of a hard reset
+        clear_triggered_exception();
+        if let Err(e) = reset {
+            // Skip the test-case, the next execution resets with `snapshot.on_crash`
+            let dir = get_run_conf().unwrap().run_dir.join("reset_failures");
+            let name = e.store(&dir, reset_level, &previous_input);
+            log::error!(
+                "{:?} reset failed after test-case {}: {}",
+                reset_level,
+                name,
+                e
+            );
+            set_last_exit(ExitInfo {
+                reason: ExitReason::ResetFailure,
+                pc: rs.pc().unwrap_or_default(),
+                hit: None,
+                exception: None,
+                reset_level,
+            });
+            counter_snapshot = 0;
+            is_crash_snapshot = true;
+            previous_input = input.target_bytes().as_slice().to_vec();
+            // The feedback and the objective reject the test-case by its `ExitReason`
+            return ExitKind::Ok;
         }
-        let mut buffer = vec![0; conf.input.total_size()];
-        buffer[..target_buf.len()].copy_from_slice(target_buf);
-        let mut buffer = buffer.as_slice();
-        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
-        for mem in conf.input.mem.iter() {
-            unsafe {
-                write_flash_mem(mem.addr, &buffer[..mem.size]);
+        // Compare with the snapshot, a divergence is caused by the previous test-case
+        if let Some(check_period) = conf.snapshot.check_period {
+            counter_check += 1;
+            if counter_check >= check_period {
+                counter_check = 0;
+                check_reset(&rs, &emu, reset_level, &previous_input);
             }
-            buffer = &buffer[mem.size..];
         }
+        previous_input = input.target_bytes().as_slice().to_vec();
+        // Not every reset restores CP15 and a warm translation cache hides the `MCR`s,
+        // check the vector base before every execution
+        eh.rearm(&emu);
 
-        // Fixed values to memory
-        for &FixedConfig { addr, val: value } in conf.input.fixed.iter() {
-            let buffer = value.to_ne_bytes();
-            unsafe {
-                write_flash_mem(addr, &buffer);
+        #[cfg(feature = "debug")]
+        print_input(input.bytes());
+
+        // The input goes to the flash or, in the mailbox mode, is a sequence of commands
+        let mut commands = Vec::new().into_iter();
+        match &conf.mailbox {
+            Some(mailbox) => {
+                reset_idle(&emu, mailbox);
+                commands = parse_commands(input.target_bytes().as_slice(), mailbox).into_iter();
+                if let Some(command) = commands.next() {
+                    send_command(&emu, mailbox, &command);
+                }
             }
+            None => write_input(&conf.input, &flash_base, input.target_bytes().as_slice()),
         }
+        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
 
         // Start the emulation
         let mut pc: u32 = cpu.read_reg(Regs::Pc).unwrap();
         log::debug!("Start at {:#x}", pc);
-        unsafe {
-            match emu.run() {
-                Ok(QemuExitReason::Breakpoint(_)) => {}
-                Ok(QemuExitReason::End(QemuShutdownCause::HostSignal(Signal::SigInterrupt))) => {
-                    process::exit(CTRL_C_EXIT)
+        start_block_budget();
+        let start = Instant::now();
+        let exit = loop {
+            let exit = unsafe { emu.run() };
+            // The guest relocated its exception vectors, move the hooks and continue
+            if ExceptionHandler::rearm_requested() {
+                eh.rearm(&emu);
+                continue;
+            }
+            if let Some(mailbox) = &conf.mailbox {
+                // The workloop took the last command, stop at the idle loop again
+                if take_left_idle(&emu, mailbox) {
+                    continue;
                 }
-                Err(QemuExitError::UnexpectedExit) => {
-                    log::error!(
-                        "Got unexpected crash at {:#x}",
-                        cpu.read_reg::<_, u32>(Regs::Pc).unwrap()
-                    );
-                    return ExitKind::Crash;
+                // The workloop is idle, the next command is sent once the PSP answered the last one
+                if let Ok(QemuExitReason::Breakpoint(pc)) = &exit {
+                    if *pc == mailbox.idle {
+                        if let Some(response) = mailbox_response(&emu, mailbox) {
+                            log::debug!("Mailbox response {:#010x}", response);
+                            if let Some(command) = commands.next() {
+                                send_from_idle(&emu, mailbox, &command);
+                                continue;
+                            }
+                        }
+                    }
                 }
-                _ => panic!("Unexpected QEMU exit."),
             }
+            break exit;
         };
+        match exit {
+            Ok(QemuExitReason::Breakpoint(_)) => {}
+            Ok(QemuExitReason::End(QemuShutdownCause::HostSignal(Signal::SigInterrupt))) => {
+                process::exit(CTRL_C_EXIT)
+            }
+            Err(QemuExitError::UnexpectedExit) => {
+                let pc: GuestAddr = cpu.read_reg(Regs::Pc).unwrap();
+                log::error!("Got unexpected crash at {:#x}", pc);
+                set_last_exit(ExitInfo {
+                    reason: ExitReason::UnexpectedExit,
+                    pc,
+                    hit: None,
+                    exception: None,
+                    reset_level,
+                });
+                return ExitKind::Crash;
+            }
+            _ => panic!("Unexpected QEMU exit."),
+        }
 
         // After the emulator finished
         pc = cpu.read_reg(Regs::Pc).unwrap();
         let r0: u64 = cpu.read_reg(Regs::R0).unwrap();
         log::debug!("End at {:#x} with R0={:#x}", pc, r0);
         counter_snapshot += 1;
+        // In the mailbox mode the workloop going idle ends the execution as well
+        let is_sink = |pc: GuestAddr| {
+            conf.harness.sinks.contains(&pc) || conf.mailbox.as_ref().is_some_and(|m| m.idle == pc)
+        };
+
+        // Stopped by the block budget, the executor's timeout or the replay deadline,
+        // somewhere in the guest
+        let hang = if block_budget_exhausted() {
+            Some(ExitReason::BlockBudget)
+        } else if deadline_passed()
+            || start.elapsed().as_millis() >= u128::from(conf.harness.timeout_ms) && !is_sink(pc)
+        {
+            Some(ExitReason::Timeout)
+        } else {
+            None
+        };
+        if let Some(reason) = hang {
+            set_last_exit(ExitInfo {
+                reason,
+                pc,
+                hit: None,
+                exception: None,
+                reset_level,
+            });
+            counter_snapshot = 0;
+            is_crash_snapshot = true;
+
+            log::info!("Found hang at {:#x} ({:?})", pc, reason);
+            return ExitKind::Timeout;
+        }
+
+        let exception = ExceptionHandler::exception_type(&pc);
+        if exception.is_some() {
+            ExceptionHandler::read_fault_registers(&emu);
+        }
+        let reason = if is_sink(pc) {
+            ExitReason::Sink
+        } else if exception.is_some() {
+            ExitReason::Exception
+        } else if conf
+            .crashes
+            .breakpoints
+            .iter()
+            .any(|&v| v == pc as GuestAddr)
+        {
+            ExitReason::CrashBreakpoint
+        } else {
+            ExitReason::Crash
+        };
+        let hit = match reason {
+            ExitReason::Sink | ExitReason::CrashBreakpoint => Some(pc),
+            _ => None,
+        };
+        set_last_exit(ExitInfo {
+            reason,
+            pc,
+            hit,
+            exception,
+            reset_level,
+        });
         // Look for crashes if no sink was hit
-        if !conf.harness.sinks.iter().any(|&v| v == pc as GuestAddr) {
-            // Don't trigger on exceptions
-            if !ExceptionHandler::is_exception_handler_addr(&pc) {
-                counter_snapshot = 0;
-                is_crash_snapshot = true;
+        // Don't trigger on exceptions
+        if reason == ExitReason::CrashBreakpoint || reason == ExitReason::Crash {
+            counter_snapshot = 0;
+            is_crash_snapshot = true;
 
-                log::info!("Found crash at {:#x}", pc);
-                return ExitKind::Crash;
-            }
+            log::info!("Found crash at {:#x}", pc);
+            return ExitKind::Crash;
         }
         log::debug!("End harness");
         ExitKind::Ok
     }
 }
 
+/// Input, fixed values and checksums to memory
+fn write_input(input_conf: &InputConfig, flash_base: &[u8], input: &[u8]) {
+    let mut writes = input_conf.flash_writes(input);
+    fix_checksums(flash_base, input_conf, &mut writes);
+    for (addr, buffer) in writes {
+        unsafe {
+            write_flash_mem(addr, &buffer);
+        }
+    }
+}
+
+/// Reports what the reset left different from the snapshot
+fn check_reset(rs: &ResetState, emu: &Qemu, reset_level: ResetLevel, previous_input: &[u8]) {
+    let divergence = rs.check(emu);
+    if divergence.is_empty() {
+        log::debug!("{:?} reset matches the snapshot", reset_level);
+        return;
+    }
+    let dir = get_run_conf().unwrap().run_dir.join("divergences");
+    let name = divergence.store(&dir, reset_level, previous_input);
+    log::warn!(
+        "{:?} reset diverged from the snapshot after test-case {}:\n{}",
+        reset_level,
+        name,
+        divergence
+    );
+}
+
 #[cfg(feature = "debug")]
 fn print_input(input: &[u8]) {
     let mut out_str = "input=[\n".to_string();
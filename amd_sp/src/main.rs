@@ -1,9 +1,13 @@
 mod client;
 #[cfg(all(target_os = "linux", not(feature = "performance")))]
+mod export;
+#[cfg(all(target_os = "linux", not(feature = "performance")))]
 mod fuzzer;
 mod harness;
 #[cfg(all(target_os = "linux", feature = "performance"))]
 mod performance;
+#[cfg(all(target_os = "linux", not(feature = "performance")))]
+mod replay;
 mod setup;
 
 #[cfg(target_os = "linux")]
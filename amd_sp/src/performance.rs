@@ -9,13 +9,14 @@ use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
 use std::time::Duration;
 use std::time::Instant;
 
 static mut ZEN_GENERATION: Option<String> = None;
-static mut SRAM_SIZE: Option<GuestAddr> = None;
+static mut MEMORY: Option<MemoryConfig> = None;
 static mut ENTRY_POINT: Option<GuestAddr> = None;
 static mut DIR_OFFSET: Option<GuestAddr> = None;
 static mut PARSE_DIR: Option<GuestAddr> = None;
@@ -25,6 +26,9 @@ static mut LOAD_APP: Option<GuestAddr> = None;
 static mut VERIFY_APP: Option<GuestAddr> = None;
 static mut CALL_OFF_CHIP: Option<GuestAddr> = None;
 
+/// Entry point of the off-chip bootloader
+const OFF_CHIP_ENTRY: GuestAddr = 0x100;
+
 #[allow(unused_mut)]
 extern "C" fn on_vcpu(mut cpu: CPU) {
     let emu = cpu.emulator();
@@ -37,8 +41,9 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     reset_state_dir.push(format!("{zen_generation}.stats"));
     let mut rs_file = File::create(reset_state_dir).unwrap();
 
-    let sram_size: GuestAddr = unsafe { *SRAM_SIZE.as_ref().unwrap() };
-    let mut rs = ResetState::new(sram_size);
+    let memory = unsafe { MEMORY.as_ref().unwrap() };
+    track_dirty_pages(memory);
+    let mut rs = ResetState::new(memory);
 
     // Go to FUZZ_START
     let entry_point = unsafe { ENTRY_POINT.as_ref().unwrap() };
@@ -55,7 +60,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     emu.remove_breakpoint(*entry_point);
 
     // Save emulator state
-    rs.save(&emu, &ResetLevel::RustSnapshot);
+    rs.save(&emu, &ResetLevel::RustSnapshot).unwrap();
 
     let num_iter = 10000;
 
@@ -64,13 +69,16 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         ResetLevel::SuperLazy,
         ResetLevel::Lazy,
         ResetLevel::RustSnapshot,
+        ResetLevel::DirtyPages,
+        ResetLevel::QemuSnapshot,
+        ResetLevel::FastSnapshot,
         ResetLevel::HardReset,
     ];
     write!(rs_file, "Averaged over {} iterations:\n", num_iter).unwrap();
     for lev in &reset_level {
         let start = Instant::now();
         for _ in 0..num_iter {
-            rs.load(&emu, lev);
+            rs.load(&emu, lev).unwrap();
         }
         let duration = start.elapsed();
         write!(
@@ -82,15 +90,43 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         .unwrap();
     }
 
-    // Flash parsing runtime
-    emu.set_breakpoint(0x100 as GuestAddr);
+    // Resetting after a test-case, DirtyPages and FastSnapshot only restore what the parsing wrote
+    let call_off_chip = unsafe { *CALL_OFF_CHIP.as_ref().unwrap() };
+    emu.set_breakpoint(call_off_chip);
+    write!(rs_file, "\nReset after parsing the flash:\n").unwrap();
+    for lev in &[
+        ResetLevel::RustSnapshot,
+        ResetLevel::DirtyPages,
+        ResetLevel::FastSnapshot,
+    ] {
+        rs.load(&emu, &ResetLevel::RustSnapshot).unwrap();
+        let mut total_time: Duration = Duration::from_secs(0);
+        for _ in 0..num_iter {
+            emu.start(&cpu);
+            let start = Instant::now();
+            rs.load(&emu, lev).unwrap();
+            total_time += start.elapsed();
+        }
+        write!(
+            rs_file,
+            "{:12}\t\t{:>12}/iter\n",
+            format!("{:?}:", lev),
+            format!("{:?}", total_time / num_iter)
+        )
+        .unwrap();
+    }
+    emu.remove_breakpoint(call_off_chip);
+    rs.load(&emu, &ResetLevel::RustSnapshot).unwrap();
+
+    // Flash parsing runtime, until the off-chip bootloader is entered
+    emu.set_breakpoint(OFF_CHIP_ENTRY);
     let mut total_time: Duration = Duration::from_secs(0);
     for _ in 0..num_iter {
         let start = Instant::now();
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     total_time = total_time / num_iter;
     write!(
@@ -100,7 +136,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *DIR_OFFSET.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -108,7 +144,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *DIR_OFFSET.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -119,7 +155,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *PARSE_DIR.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -127,7 +163,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *PARSE_DIR.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -138,7 +174,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *COPY_PUBKEY.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -146,7 +182,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *COPY_PUBKEY.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -157,7 +193,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *VERIFY_PUBKEY.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -165,7 +201,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *VERIFY_PUBKEY.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -176,7 +212,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *LOAD_APP.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -184,7 +220,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *LOAD_APP.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -195,7 +231,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *VERIFY_APP.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -203,7 +239,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *VERIFY_APP.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -214,7 +250,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
     )
     .unwrap();
 
-    rs.load(&emu, &ResetLevel::HardReset);
+    rs.load(&emu, &ResetLevel::HardReset).unwrap();
     emu.set_breakpoint(unsafe { *CALL_OFF_CHIP.as_ref().unwrap() });
     total_time = Duration::from_secs(0);
     for _ in 0..num_iter {
@@ -222,7 +258,7 @@ extern "C" fn on_vcpu(mut cpu: CPU) {
         emu.start(&cpu);
         let duration = start.elapsed();
         total_time += duration;
-        rs.load(&emu, &ResetLevel::HardReset);
+        rs.load(&emu, &ResetLevel::HardReset).unwrap();
     }
     emu.remove_breakpoint(unsafe { *CALL_OFF_CHIP.as_ref().unwrap() });
     total_time = total_time / num_iter;
@@ -268,81 +304,37 @@ fn parse_args() -> Vec<String> {
     let zen_generation: &str;
     let on_chip_bl: &str;
     let uefi_image: &str;
+    let memory: MemoryConfig;
     if cli_args.zen_generation.as_ref().unwrap() == &String::from("Zen1") {
         zen_generation = "amd-psp-zen";
         on_chip_bl = "bins/on-chip-bl-Ryzen-Zen1-Desktop";
         uefi_image = "bins/PRIME-X370-PRO-ASUS-3803.ROM";
-        unsafe {
-            SRAM_SIZE = Some(0x4_0000);
-            ENTRY_POINT = Some(0xffff_4be4);
-            DIR_OFFSET = Some(0xffff_4af8);
-            PARSE_DIR = Some(0xffff_42d0);
-            COPY_PUBKEY = Some(0xffff_442c);
-            VERIFY_PUBKEY = Some(0xffff_44cc);
-            LOAD_APP = Some(0xffff_45f8);
-            VERIFY_APP = Some(0xffff_481c);
-            CALL_OFF_CHIP = Some(0xffff_48e4);
-        }
+        memory = ZenVersion::Zen1.memory();
     } else if cli_args.zen_generation.as_ref().unwrap() == &String::from("Zen+") {
         zen_generation = "amd-psp-zen+";
         on_chip_bl = "bins/on-chip-bl-Ryzen-Zen+-Desktop";
         uefi_image = "bins/PRIME-X370-PRO-ASUS-3803.ROM";
-        unsafe {
-            SRAM_SIZE = Some(0x4_0000);
-            ENTRY_POINT = Some(0xffff_4b90);
-            DIR_OFFSET = Some(0xffff_4aa4);
-            PARSE_DIR = Some(0xffff_427c);
-            COPY_PUBKEY = Some(0xffff_43d8);
-            VERIFY_PUBKEY = Some(0xffff_4478);
-            LOAD_APP = Some(0xffff_45a4);
-            VERIFY_APP = Some(0xffff_47c8);
-            CALL_OFF_CHIP = Some(0xffff_4890);
-        }
+        memory = ZenVersion::ZenPlus.memory();
     } else if cli_args.zen_generation.as_ref().unwrap() == &String::from("Zen2") {
         zen_generation = "amd-psp-zen2";
         on_chip_bl = "bins/on-chip-bl-Ryzen-Zen2-Desktop";
         uefi_image = "bins/ASUS_PRIME-B450M-A-ASUS-1201.ROM";
-        unsafe {
-            SRAM_SIZE = Some(0x5_0000);
-            ENTRY_POINT = Some(0xffff_2bf8);
-            DIR_OFFSET = Some(0xffff_27b0);
-            PARSE_DIR = Some(0xffff_1fc4);
-            COPY_PUBKEY = Some(0xffff_2140);
-            VERIFY_PUBKEY = Some(0xffff_21f4);
-            LOAD_APP = Some(0xffff_2908);
-            VERIFY_APP = Some(0xffff_23b0);
-            CALL_OFF_CHIP = Some(0xffff_24b8);
-        }
+        memory = ZenVersion::Zen2.memory();
     } else if cli_args.zen_generation.as_ref().unwrap() == &String::from("Zen3") {
         zen_generation = "amd-psp-zen3";
         on_chip_bl = "bins/on-chip-bl-Ryzen-Zen3-Desktop";
         uefi_image = "bins/ASUS_PRIME-B450M-A-ASUS-1201.ROM";
-        unsafe {
-            SRAM_SIZE = Some(0x5_0000);
-            ENTRY_POINT = Some(0xffff_2bf8);
-            DIR_OFFSET = Some(0xffff_27b0);
-            PARSE_DIR = Some(0xffff_1fc4);
-            COPY_PUBKEY = Some(0xffff_2140);
-            VERIFY_PUBKEY = Some(0xffff_21f4);
-            LOAD_APP = Some(0xffff_2908);
-            VERIFY_APP = Some(0xffff_23b0);
-            CALL_OFF_CHIP = Some(0xffff_24b8);
-        }
+        memory = ZenVersion::Zen3.memory();
     } else if cli_args.zen_generation.as_ref().unwrap() == &String::from("ZenTesla") {
         zen_generation = "amd-psp-zentesla";
         on_chip_bl = "bins/on-chip-bl-Ryzen-ZenTesla";
         uefi_image = "bins/ZenTesla-BIOS-first-half.bin";
-        unsafe {
-            SRAM_SIZE = Some(0x4_0000);
-            ENTRY_POINT = Some(0xffff_4650);
-            DIR_OFFSET = Some(0xffff_434c);
-            PARSE_DIR = Some(0xffff_3b7c);
-            COPY_PUBKEY = Some(0xffff_3cf0);
-            VERIFY_PUBKEY = Some(0xffff_3d90);
-            LOAD_APP = Some(0xffff_3ebc);
-            VERIFY_APP = Some(0xffff_410c);
-            CALL_OFF_CHIP = Some(0xffff_41d4);
-        }
+        memory = ZenVersion::ZenTesla.memory();
+    } else if cli_args.zen_generation.as_ref().unwrap() == &String::from("Zen4") {
+        zen_generation = ZenVersion::Zen4.get_qemu_machine_name();
+        on_chip_bl = "bins/on-chip-bl-Ryzen-Zen4-Desktop";
+        uefi_image = "bins/Zen4-Desktop.ROM";
+        memory = ZenVersion::Zen4.memory();
     } else {
         println!(
             "{} generation not supported yet.",
@@ -350,6 +342,29 @@ fn parse_args() -> Vec<String> {
         );
         std::process::exit(3);
     }
+    // The measured functions, from the symbol file shipped next to the bootloader
+    let symbols_path = PathBuf::from(format!("{on_chip_bl}.nm"));
+    let symbols = YAMLConfig::load_symbols(&symbols_path, Path::new(".")).unwrap_or_else(|e| {
+        println!("{e}");
+        std::process::exit(3);
+    });
+    let lookup = |name: &str| {
+        Some(symbols.get(name).unwrap_or_else(|| {
+            println!("Symbol `{name}` not found in {}", symbols_path.display());
+            std::process::exit(3);
+        }))
+    };
+    unsafe {
+        MEMORY = Some(memory);
+        ENTRY_POINT = lookup("parse_psp_flash");
+        DIR_OFFSET = lookup("get_dir_offset");
+        PARSE_DIR = lookup("parse_psp_dir");
+        COPY_PUBKEY = lookup("copy_pubkey");
+        VERIFY_PUBKEY = lookup("verify_pubkey");
+        LOAD_APP = lookup("load_app");
+        VERIFY_APP = lookup("verify_app");
+        CALL_OFF_CHIP = lookup("call_off_chip");
+    }
     unsafe {
         ZEN_GENERATION = Some(cli_args.zen_generation.unwrap());
     }
@@ -391,6 +406,9 @@ pub fn fuzz() {
 
     // Start emulator
     let emu = Qemu::init(&qemu_args, &env);
+    // Dirty SRAM pages for the DirtyPages reset level
+    let hooks = QemuHooks::<(), NopState<BytesInput>>::new(emu, ());
+    setup_dirty_page_hooks(&hooks);
     emu.set_vcpu_start(on_vcpu);
     unsafe {
         emu.run();
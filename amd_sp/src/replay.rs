@@ -0,0 +1,100 @@
+use libafl::prelude::*;
+use libafl_bolts::prelude::*;
+use libafl_qemu::*;
+use libasp::{
+    check_deadline, count_tunnel_hits, get_run_conf, injecting, last_exit, limit_wall_clock,
+    reg_name, reset_tunnel_hits, take_triggered_exception, tunnel_hits,
+};
+
+use std::{env, fs, path::Path, time::Duration};
+
+use crate::client::{setup_crash_hooks, MyState};
+use crate::harness;
+
+/// Executes a single test-case with the config of a previous run and
+/// prints how the execution ended
+pub fn replay(qemu_args: Vec<String>, testcase: &Path) {
+    let conf = &get_run_conf().unwrap().yaml_config;
+    let env: Vec<(String, String)> = env::vars().collect();
+    let input = BytesInput::new(fs::read(testcase).unwrap());
+
+    let emu = Qemu::init(&qemu_args, &env).unwrap();
+
+    // Same tunnels and crash detection as during fuzzing, but no coverage
+    let hooks = QemuHooks::<_, MyState>::new(emu, tuple_list!());
+    setup_crash_hooks(&hooks, conf);
+    // Without the executor's timeout, the block hooks stop an execution at the deadline
+    limit_wall_clock(Duration::from_millis(conf.harness.timeout_ms));
+    hooks.blocks(
+        Hook::Function(gen_deadline_block_hook),
+        Hook::Empty,
+        Hook::Function(exec_deadline_block_hook),
+    );
+
+    // Run to the harness start and save the state there
+    let (rs, eh) = harness::prepare(emu);
+    reset_tunnel_hits();
+    count_tunnel_hits(true);
+
+    let mut harness = harness::create_harness(rs, eh, emu);
+    let exit_kind = harness(&input);
+
+    // Report the final state
+    let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+    let pc: GuestAddr = cpu.read_reg(Regs::Pc).unwrap();
+    println!("Test-case: {}", testcase.display());
+    println!("Exit kind: {:?}", exit_kind);
+    println!("Exit PC:   {:#010x}", pc);
+    if exit_kind == ExitKind::Timeout {
+        println!("Hang reproduced");
+    }
+    if let Some(exit) = last_exit() {
+        println!("Reason:    {:?}", exit.reason);
+        if let Some(hit) = exit.hit {
+            println!("Hit:       {:#010x}", hit);
+        }
+        match exit.exception {
+            Some(exception) => println!("Exception: {:?}", exception),
+            None => println!("Exception: -"),
+        }
+    }
+    if let Some(exception) = take_triggered_exception() {
+        println!("{:#x?}", exception);
+    }
+    println!("Tunnel hits:");
+    for (addr, hits) in tunnel_hits() {
+        println!("\t{:#010x}: {}", addr, hits);
+    }
+    println!("Registers:");
+    for r in Regs::iter() {
+        let value: u32 = cpu.read_reg(r).unwrap();
+        println!("\t{} =\t{:#010x}", reg_name(r), value);
+    }
+}
+
+fn gen_deadline_block_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _id: Option<&mut S>,
+    _src: GuestAddr,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    Some(0)
+}
+fn exec_deadline_block_hook<QT, S>(
+    hooks: &mut QemuHooks<QT, S>,
+    _data_maybe: Option<&mut S>,
+    _id: u64,
+) where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    if injecting() {
+        return;
+    }
+    if check_deadline() {
+        hooks.qemu().current_cpu().unwrap().trigger_breakpoint();
+    }
+}
@@ -1,54 +1,219 @@
 use chrono::Local;
-use clap::{command, Parser};
+use clap::{command, Parser, Subcommand};
 
-use libasp::{borrow_global_conf, init_global_conf};
+use libasp::{
+    borrow_global_conf, init_global_conf, ConfigOverride, Diagnostic, YAMLConfig, ON_CHIP_BL_ADDR,
+};
 
-use std::{env, path::PathBuf, process::exit};
+use libafl_bolts::core_affinity::Cores;
+
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 /// Fuzzing the on-chip-bootloader from different AMD Zen generations.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)] // Read from Cargo.toml
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// YAML config file path
-    #[arg(short, long)]
-    yaml_path: PathBuf,
+    #[arg(short, long, required_unless_present = "resume")]
+    yaml_path: Option<PathBuf>,
 
     /// Run directory name
     #[arg(short, long)]
     run_dir_name: Option<String>,
 
+    /// Continue the campaign in the run directory with its stored config
+    #[arg(long, requires = "run_dir_name", conflicts_with = "yaml_path")]
+    resume: bool,
+
     /// Number of cores
     #[arg(short, long)]
     num_cores: Option<u32>,
+
+    /// Cores to fuzz on, e.g. `0-3,8` [default: `0-{num_cores}`]
+    #[arg(short, long, conflicts_with = "num_cores")]
+    cores: Option<String>,
+
+    /// Port of the broker, campaigns running on the same host need different ports
+    #[arg(long, default_value_t = 1337)]
+    broker_port: u16,
+
+    /// Broker of a campaign on another host to share test-cases and objectives with,
+    /// e.g. `10.0.0.2:1337`
+    #[arg(long, value_name = "ADDR:PORT")]
+    remote_broker: Option<SocketAddr>,
+
+    /// Override a config value, e.g. `--set snapshot.default=Lazy` (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE", conflicts_with = "resume")]
+    overrides: Vec<ConfigOverride>,
 }
 
-pub fn parse_args() -> Vec<String> {
-    let cli_args = Args::parse();
-    // Parse YAML config
-    if !cli_args.yaml_path.exists() {
-        println!(
-            "YAML file path does not exist: {}",
-            cli_args.yaml_path.display()
-        );
-        exit(2);
-    }
-    let date = Local::now();
-    let run_dir = if let Some(run_dir_name) = cli_args.run_dir_name {
-        PathBuf::from(format!("runs/{}", run_dir_name))
-    } else {
-        PathBuf::from(format!("runs/{}", date.format("%Y-%m-%d_%H:%M")))
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Re-execute a single test-case against the config of a previous run
+    Replay {
+        /// Run directory of the campaign (`runs/{start_data_time}/`)
+        run_dir: PathBuf,
+        /// Test-case to execute, e.g. a file from `{run_dir}/solutions/`
+        testcase: PathBuf,
+        /// Wall-clock limit in milliseconds [default: `harness.timeout_ms` of the run]
+        #[arg(short, long)]
+        timeout: Option<u64>,
+    },
+    /// Build full flash images from all solutions of a previous run
+    Export {
+        /// Run directory of the campaign (`runs/{start_data_time}/`)
+        run_dir: PathBuf,
+        /// Output directory [default: `{run_dir}/solutions/full_img/`]
+        #[arg(short, long)]
+        out_dir: Option<PathBuf>,
+    },
+    /// Check a YAML config without starting the emulator
+    CheckConfig {
+        /// YAML config file path
+        yaml_path: PathBuf,
+        /// Override a config value, e.g. `--set snapshot.default=Lazy` (repeatable)
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        overrides: Vec<ConfigOverride>,
+    },
+}
+
+/// Prints the diagnostics of a config and returns whether it can be used
+fn check_config(config_path: &Path, overrides: &[ConfigOverride]) -> bool {
+    let diagnostics = match YAMLConfig::load_with(config_path, overrides) {
+        Ok(conf) => conf.validate(),
+        Err(diagnostic) => vec![diagnostic],
     };
-    let num_cores = if let Some(num_cores) = cli_args.num_cores {
-        num_cores
-    } else {
-        println!("For multicore fuzzing a core number must be provided (`cargo make run_fast -h`)");
-        exit(3);
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    !diagnostics.iter().any(Diagnostic::is_error)
+}
+
+/// How the fuzzer processes are spread over cores and hosts
+pub struct LaunchConfig {
+    pub cores: Cores,
+    pub broker_port: u16,
+    /// Broker on another host this campaign's broker connects to
+    pub remote_broker: Option<SocketAddr>,
+}
+
+/// What the fuzzer binary has been asked to do
+pub enum Mode {
+    /// Start a new fuzzing campaign or resume an existing one
+    Fuzz { resume: bool, launch: LaunchConfig },
+    /// Execute a single test-case once and report how it ended
+    Replay { testcase: PathBuf },
+    /// Write a full flash image for every solution
+    Export { out_dir: PathBuf },
+}
+
+pub fn parse_args() -> (Mode, Vec<String>) {
+    let cli_args = Args::parse();
+    let mode = match cli_args.command {
+        Some(Command::CheckConfig {
+            yaml_path,
+            overrides,
+        }) => {
+            if !check_config(&yaml_path, &overrides) {
+                exit(7);
+            }
+            println!("{} is valid", yaml_path.display());
+            exit(0);
+        }
+        Some(Command::Replay {
+            run_dir,
+            testcase,
+            timeout,
+        }) => {
+            // Parse the YAML config that was stored with the run
+            let config_path = run_dir.join("config.yaml");
+            if !config_path.exists() {
+                println!("YAML file path does not exist: {}", config_path.display());
+                exit(2);
+            }
+            if !testcase.exists() {
+                println!("Test-case file path does not exist: {}", testcase.display());
+                exit(6);
+            }
+            // The harness reports a hang once the limit passed
+            let overrides = timeout
+                .map(|ms| format!("harness.timeout_ms={ms}").parse().unwrap())
+                .into_iter()
+                .collect();
+            init_global_conf(&config_path, 1, run_dir, overrides);
+            Mode::Replay { testcase }
+        }
+        Some(Command::Export { run_dir, out_dir }) => {
+            let config_path = run_dir.join("config.yaml");
+            if !config_path.exists() {
+                println!("YAML file path does not exist: {}", config_path.display());
+                exit(2);
+            }
+            let out_dir = out_dir.unwrap_or_else(|| run_dir.join("solutions").join("full_img"));
+            init_global_conf(&config_path, 1, run_dir, Vec::new());
+            Mode::Export { out_dir }
+        }
+        None => {
+            let date = Local::now();
+            let run_dir = if let Some(run_dir_name) = cli_args.run_dir_name {
+                PathBuf::from(format!("runs/{}", run_dir_name))
+            } else {
+                PathBuf::from(format!("runs/{}", date.format("%Y-%m-%d_%H:%M")))
+            };
+            // Parse YAML config, a resumed campaign uses the one stored with the run
+            let yaml_path = if cli_args.resume {
+                run_dir.join("config.yaml")
+            } else {
+                cli_args.yaml_path.unwrap()
+            };
+            if !yaml_path.exists() {
+                println!("YAML file path does not exist: {}", yaml_path.display());
+                exit(2);
+            }
+            let cores = match (cli_args.cores, cli_args.num_cores) {
+                (Some(cores), _) => cores,
+                (None, Some(num_cores)) => format!("0-{num_cores}"),
+                (None, None) => {
+                    println!(
+                        "For multicore fuzzing a core number or list must be provided (`cargo make run_fast -h`)"
+                    );
+                    exit(3);
+                }
+            };
+            let cores = match Cores::from_cmdline(&cores) {
+                Ok(cores) => cores,
+                Err(err) => {
+                    println!("Invalid core list `{cores}`: {err}");
+                    exit(3);
+                }
+            };
+            init_global_conf(
+                &yaml_path,
+                cores.ids.len() as u32,
+                run_dir,
+                cli_args.overrides,
+            );
+            Mode::Fuzz {
+                resume: cli_args.resume,
+                launch: LaunchConfig {
+                    cores,
+                    broker_port: cli_args.broker_port,
+                    remote_broker: cli_args.remote_broker,
+                },
+            }
+        }
     };
-    init_global_conf(&cli_args.yaml_path, num_cores, run_dir);
     let conf = borrow_global_conf().unwrap();
 
-    // For multicore fuzzing a core number must be provided
-
     //Check if pathes exist
     if !conf.qemu.on_chip_bl_path.exists() {
         println!(
@@ -64,6 +229,15 @@ pub fn parse_args() -> Vec<String> {
         );
         exit(5);
     }
+    // Semantic checks of the config
+    let diagnostics = conf.validate();
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        println!("Invalid config, check it with `aspfuzz check-config`");
+        exit(7);
+    }
 
     // Create arguments to start QEMU with
     let mut qemu_args: Vec<String> = vec![env::args().next().unwrap()];
@@ -83,9 +257,10 @@ pub fn parse_args() -> Vec<String> {
         "--nographic".to_string(),
         "-device".to_string(),
         format![
-            "loader,file={}/{},addr=0xffff0000,force-raw=on",
+            "loader,file={}/{},addr={:#x},force-raw=on",
             env::var("PROJECT_DIR").unwrap(),
-            &conf.qemu.on_chip_bl_path.display()
+            &conf.qemu.on_chip_bl_path.display(),
+            ON_CHIP_BL_ADDR
         ],
         "-global".to_string(),
         format![
@@ -101,5 +276,5 @@ pub fn parse_args() -> Vec<String> {
         ],
     ]);
 
-    qemu_args
+    (mode, qemu_args)
 }
@@ -0,0 +1,371 @@
+/// Bucketing objectives by fault site and call stack
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use libafl::prelude::*;
+use libafl_bolts::{current_time, impl_serdeany, AsSlice, Named};
+use libafl_qemu::*;
+use log;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use crate::{last_exit, triggered_exception, ExceptionType};
+
+/// Maximum distance between two frame records on the stack
+const MAX_FRAME_SIZE: GuestAddr = 0x1000;
+
+/// 64-bit FNV-1a, stable across runs and Rust versions
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Return addresses found by following the frame pointer chain.
+///
+/// The bootloaders are Thumb code, so a frame record `{r7, lr}` is pushed
+/// and R7 points to it. The LR of the crashing function is the first frame.
+pub fn unwind_stack<F>(
+    lr: GuestAddr,
+    fp: GuestAddr,
+    sp: GuestAddr,
+    depth: usize,
+    is_code: impl Fn(GuestAddr) -> bool,
+    read_word: F,
+) -> Vec<GuestAddr>
+where
+    F: Fn(GuestAddr) -> Option<u32>,
+{
+    let mut frames = Vec::new();
+    if depth == 0 {
+        return frames;
+    }
+    if is_code(lr & !1) {
+        frames.push(lr & !1);
+    }
+    let mut fp = fp;
+    let mut lower = sp;
+    while frames.len() < depth {
+        // Frame records live above the stack pointer and grow towards higher addresses
+        if fp < lower || fp - lower > MAX_FRAME_SIZE || fp % 4 != 0 {
+            break;
+        }
+        let (Some(next_fp), Some(ret)) = (read_word(fp), read_word(fp + 4)) else {
+            break;
+        };
+        if !is_code(ret & !1) {
+            break;
+        }
+        frames.push(ret & !1);
+        lower = fp + 8;
+        fp = next_fp;
+    }
+    frames
+}
+
+/// What identifies a crash bucket
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CrashSignature {
+    pub pc: GuestAddr,
+    pub exception: Option<ExceptionType>,
+    pub frames: Vec<GuestAddr>,
+}
+
+impl CrashSignature {
+    /// Name of the bucket, used as directory name
+    pub fn id(&self) -> String {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.pc.to_le_bytes());
+        data.push(self.exception.map_or(0xff, |e| e as u8));
+        for frame in &self.frames {
+            data.extend_from_slice(&frame.to_le_bytes());
+        }
+        format!("{:016x}", fnv1a(&data))
+    }
+}
+
+/// Summary of a bucket in `buckets/index.json`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BucketEntry {
+    pub signature: CrashSignature,
+    /// Seconds since the epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub hits: u64,
+    pub solutions: u64,
+}
+
+/// The on-disk bucket index, shared between all clients of a campaign
+#[derive(Debug)]
+pub struct BucketIndex {
+    dir: PathBuf,
+}
+
+impl BucketIndex {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("index.json")
+    }
+
+    /// Runs `f` on the index while holding an advisory lock on `index.lock`.
+    /// The lock is released by the OS when a client dies, so the file stays.
+    fn with_index<R>(&self, f: impl FnOnce(&mut BTreeMap<String, BucketEntry>) -> R) -> R {
+        let lock_file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join("index.lock"))
+            .unwrap();
+        let lock = Flock::lock(lock_file, FlockArg::LockExclusive)
+            .unwrap_or_else(|(_, e)| panic!("Unable to lock the bucket index: {e}"));
+        let mut index: BTreeMap<String, BucketEntry> = match fs::read(self.index_path()) {
+            Ok(content) => serde_json::from_slice(&content).unwrap(),
+            Err(_) => BTreeMap::new(),
+        };
+        let result = f(&mut index);
+        let tmp_path = self.dir.join("index.json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&index).unwrap()).unwrap();
+        fs::rename(&tmp_path, self.index_path()).unwrap();
+        // Unlocks the index
+        drop(lock);
+        result
+    }
+
+    /// Counts a hit of the bucket and returns its updated entry
+    pub fn hit(&self, signature: &CrashSignature) -> BucketEntry {
+        let now = current_time().as_secs();
+        self.with_index(|index| {
+            let entry = index.entry(signature.id()).or_insert_with(|| BucketEntry {
+                signature: signature.clone(),
+                first_seen: now,
+                last_seen: now,
+                hits: 0,
+                solutions: 0,
+            });
+            entry.hits += 1;
+            entry.last_seen = now;
+            entry.clone()
+        })
+    }
+
+    /// Stores a solution in the directory of its bucket
+    pub fn store(&self, signature: &CrashSignature, input: &[u8]) {
+        let bucket_dir = self.dir.join(signature.id());
+        fs::create_dir_all(&bucket_dir).unwrap();
+        fs::write(bucket_dir.join(format!("{:016x}", fnv1a(input))), input).unwrap();
+        self.with_index(|index| {
+            if let Some(entry) = index.get_mut(&signature.id()) {
+                entry.solutions += 1;
+            }
+        });
+    }
+}
+
+/// The bucket a solution was sorted into
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CrashBucketMetadata {
+    pub bucket: String,
+    pub signature: CrashSignature,
+}
+
+impl_serdeany!(CrashBucketMetadata);
+
+/// Keeps only the first `max_solutions` objectives of each crash bucket
+#[derive(Debug)]
+pub struct CrashBucketFeedback {
+    emulator: Qemu,
+    index: BucketIndex,
+    max_solutions: u64,
+    stack_depth: usize,
+    last: Option<CrashSignature>,
+}
+
+impl CrashBucketFeedback {
+    /// Creates a new [`CrashBucketFeedback`] storing its buckets in `dir`
+    #[must_use]
+    pub fn new(emulator: Qemu, dir: PathBuf, max_solutions: u64, stack_depth: usize) -> Self {
+        fs::create_dir_all(&dir).unwrap();
+        Self {
+            emulator,
+            index: BucketIndex::new(dir),
+            max_solutions,
+            stack_depth,
+            last: None,
+        }
+    }
+
+    fn signature(&self) -> CrashSignature {
+        let conf = crate::borrow_global_conf().unwrap();
+        let cpu = self.emulator.cpu_from_index(0);
+        let mut pc: GuestAddr = cpu.read_reg(Regs::Pc).unwrap();
+        let mut lr: GuestAddr = cpu.read_reg(Regs::Lr).unwrap();
+        let mut sp: GuestAddr = cpu.read_reg(Regs::Sp).unwrap();
+        let fp: GuestAddr = cpu.read_reg(Regs::R7).unwrap();
+        // Stopped at an exception vector, the crash site and its frames are
+        // the ones of the interrupted code
+        if let Some(exception) = triggered_exception() {
+            pc = exception.fault_pc.unwrap_or(exception.lr);
+            lr = exception.interrupted_lr.unwrap_or(lr);
+            sp = exception.interrupted_sp.unwrap_or(sp);
+        }
+        let is_code = |addr: GuestAddr| {
+            !conf
+                .crashes
+                .mmap
+                .no_exec
+                .iter()
+                .any(|region| addr >= region.begin && addr < region.end)
+        };
+        let read_word = |addr: GuestAddr| {
+            let mut buf = [0; 4];
+            unsafe { cpu.read_mem(addr, &mut buf) };
+            Some(u32::from_le_bytes(buf))
+        };
+        CrashSignature {
+            pc,
+            exception: last_exit().and_then(|exit| exit.exception),
+            frames: unwind_stack(lr, fp, sp, self.stack_depth, is_code, read_word),
+        }
+    }
+}
+
+impl<S> Feedback<S> for CrashBucketFeedback
+where
+    S: UsesInput + State,
+    S::Input: HasTargetBytes,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer,
+        OT: ObserversTuple<S>,
+    {
+        let signature = self.signature();
+        let entry = self.index.hit(&signature);
+        log::info!(
+            "Crash bucket {} hit {} times ({} solutions)",
+            signature.id(),
+            entry.hits,
+            entry.solutions
+        );
+        let interesting = entry.solutions < self.max_solutions;
+        self.last = Some(signature);
+        Ok(interesting)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _em: &mut EM,
+        _ot: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error> {
+        if let Some(signature) = self.last.take() {
+            if let Some(input) = testcase.input() {
+                self.index
+                    .store(&signature, input.target_bytes().as_slice());
+            }
+            testcase.add_metadata(CrashBucketMetadata {
+                bucket: signature.id(),
+                signature,
+            });
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.last = None;
+        Ok(())
+    }
+}
+
+impl Named for CrashBucketFeedback {
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("CrashBucketFeedback")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwind_frame_chain() {
+        // Two frame records {r7, lr} on the stack
+        let stack: BTreeMap<GuestAddr, u32> = [
+            (0x3f010, 0x3f020),
+            (0x3f014, 0xffff4001),
+            (0x3f020, 0x3f040),
+            (0x3f024, 0xffff5001),
+            (0x3f040, 0x0),
+            (0x3f044, 0x0),
+        ]
+        .into_iter()
+        .collect();
+        let frames = unwind_stack(
+            0xffff3001,
+            0x3f010,
+            0x3f000,
+            4,
+            |addr| addr >= 0xffff_0000,
+            |addr| stack.get(&addr).copied(),
+        );
+        assert_eq!(frames, vec![0xffff3000, 0xffff4000, 0xffff5000]);
+    }
+
+    #[test]
+    fn unwind_respects_depth() {
+        let frames = unwind_stack(0xffff3001, 0x3f010, 0x3f000, 1, |_| true, |_| Some(0));
+        assert_eq!(frames, vec![0xffff3000]);
+    }
+
+    #[test]
+    fn signature_id_is_stable() {
+        let signature = CrashSignature {
+            pc: 0xffff4bfc,
+            exception: Some(ExceptionType::DATAB),
+            frames: vec![0xffff3000],
+        };
+        assert_eq!(signature.id(), signature.clone().id());
+        let other = CrashSignature {
+            exception: None,
+            ..signature.clone()
+        };
+        assert_ne!(signature.id(), other.id());
+        assert_eq!(format!("{:016x}", fnv1a(b"a")), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn index_survives_stale_lock() {
+        let dir = std::env::temp_dir().join(format!("buckets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Left behind by a killed client
+        fs::write(dir.join("index.lock"), b"").unwrap();
+        let index = BucketIndex::new(dir.clone());
+        let signature = CrashSignature {
+            pc: 0xffff4bfc,
+            exception: None,
+            frames: vec![],
+        };
+        index.hit(&signature);
+        index.store(&signature, b"input");
+        let entry = index.hit(&signature);
+        assert_eq!((entry.hits, entry.solutions), (2, 1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
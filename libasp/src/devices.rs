@@ -0,0 +1,236 @@
+/// Saving and restoring the emulated PSP devices, the timers, the SMN slots, the CCP,
+/// the mailbox and the SMN flash, through the state the QEMU PSP machine exports
+use log;
+use serde::{Deserialize, Serialize};
+
+extern "C" {
+    static mut aspfuzz_timer_count_0: u64;
+    static mut aspfuzz_timer_control_0: u64;
+    static mut aspfuzz_timer_count_1: u64;
+    static mut aspfuzz_timer_control_1: u64;
+    static mut aspfuzz_smn_slots: [u32; 32];
+    /// Maps the SMN address of slot `idx` again
+    fn aspfuzz_smn_update_slot(idx: u32);
+    // Device state as an opaque blob of `*_state_size()` bytes,
+    // `*_reset_state()` puts the device into its power-on state
+    fn aspfuzz_ccp_state_size() -> usize;
+    fn aspfuzz_ccp_save_state(buf: *mut u8);
+    fn aspfuzz_ccp_restore_state(buf: *const u8);
+    fn aspfuzz_ccp_reset_state();
+    fn aspfuzz_mbox_state_size() -> usize;
+    fn aspfuzz_mbox_save_state(buf: *mut u8);
+    fn aspfuzz_mbox_restore_state(buf: *const u8);
+    fn aspfuzz_mbox_reset_state();
+    fn aspfuzz_smn_flash_state_size() -> usize;
+    fn aspfuzz_smn_flash_save_state(buf: *mut u8);
+    fn aspfuzz_smn_flash_restore_state(buf: *const u8);
+    fn aspfuzz_smn_flash_reset_state();
+}
+
+const SMN_SLOTS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    /// Count and control of both timers
+    Timers,
+    SmnSlots,
+    Ccp,
+    Mailbox,
+    /// Registers of the SMN flash controller, the flash content is the input
+    SmnFlash,
+}
+
+/// A PSP device with a state exported by QEMU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    kind: DeviceKind,
+    name: String,
+    size: usize,
+}
+
+/// State of a device, matched to the device by its name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub name: String,
+    pub state: Vec<u8>,
+}
+
+/// The PSP devices with a state exported by QEMU
+pub fn registered_devices() -> Vec<Device> {
+    vec![
+        Device {
+            kind: DeviceKind::Timers,
+            name: "timers".to_string(),
+            size: 4 * 8,
+        },
+        Device {
+            kind: DeviceKind::SmnSlots,
+            name: "smn_slots".to_string(),
+            size: SMN_SLOTS * 4,
+        },
+        Device {
+            kind: DeviceKind::Ccp,
+            name: "ccp".to_string(),
+            size: unsafe { aspfuzz_ccp_state_size() },
+        },
+        Device {
+            kind: DeviceKind::Mailbox,
+            name: "mailbox".to_string(),
+            size: unsafe { aspfuzz_mbox_state_size() },
+        },
+        Device {
+            kind: DeviceKind::SmnFlash,
+            name: "smn_flash".to_string(),
+            size: unsafe { aspfuzz_smn_flash_state_size() },
+        },
+    ]
+}
+
+fn read_timers() -> [u64; 4] {
+    unsafe {
+        [
+            aspfuzz_timer_count_0,
+            aspfuzz_timer_control_0,
+            aspfuzz_timer_count_1,
+            aspfuzz_timer_control_1,
+        ]
+    }
+}
+
+fn write_timers(timers: [u64; 4]) {
+    unsafe {
+        aspfuzz_timer_count_0 = timers[0];
+        aspfuzz_timer_control_0 = timers[1];
+        aspfuzz_timer_count_1 = timers[2];
+        aspfuzz_timer_control_1 = timers[3];
+    }
+}
+
+/// Writes the SMN slots and maps the changed ones again
+fn write_smn_slots(slots: [u32; SMN_SLOTS]) {
+    let current = unsafe { aspfuzz_smn_slots };
+    unsafe { aspfuzz_smn_slots = slots };
+    for (i, (current, slot)) in current.iter().zip(slots.iter()).enumerate() {
+        if current != slot {
+            log::debug!("SMN slot {i}: {current:#x} -> {slot:#x}");
+            unsafe { aspfuzz_smn_update_slot(i as u32) };
+        }
+    }
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the state of a device exported as an opaque blob
+    fn save_blob(&self, save: unsafe extern "C" fn(*mut u8)) -> Vec<u8> {
+        let mut state = vec![0; self.size];
+        unsafe { save(state.as_mut_ptr()) };
+        state
+    }
+
+    pub fn save(&self) -> DeviceState {
+        let state = match self.kind {
+            DeviceKind::Timers => read_timers()
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+            DeviceKind::SmnSlots => unsafe { aspfuzz_smn_slots }
+                .iter()
+                .flat_map(|slot| slot.to_le_bytes())
+                .collect(),
+            DeviceKind::Ccp => self.save_blob(aspfuzz_ccp_save_state),
+            DeviceKind::Mailbox => self.save_blob(aspfuzz_mbox_save_state),
+            DeviceKind::SmnFlash => self.save_blob(aspfuzz_smn_flash_save_state),
+        };
+        DeviceState {
+            name: self.name.clone(),
+            state,
+        }
+    }
+
+    /// Loads `state` if the device diverged from it, returns whether it did
+    pub fn restore(&self, state: &DeviceState) -> bool {
+        assert_eq!(
+            state.state.len(),
+            self.size,
+            "State of {} has the wrong size",
+            self.name
+        );
+        if self.save().state == state.state {
+            return false;
+        }
+        log::debug!("{}: resetting to the snapshot", self.name);
+        match self.kind {
+            DeviceKind::Timers => {
+                let mut timers = [0; 4];
+                for (timer, bytes) in timers.iter_mut().zip(state.state.chunks_exact(8)) {
+                    *timer = u64::from_le_bytes(bytes.try_into().unwrap());
+                }
+                write_timers(timers);
+            }
+            DeviceKind::SmnSlots => {
+                let mut slots = [0; SMN_SLOTS];
+                for (slot, bytes) in slots.iter_mut().zip(state.state.chunks_exact(4)) {
+                    *slot = u32::from_le_bytes(bytes.try_into().unwrap());
+                }
+                write_smn_slots(slots);
+            }
+            DeviceKind::Ccp => unsafe { aspfuzz_ccp_restore_state(state.state.as_ptr()) },
+            DeviceKind::Mailbox => unsafe { aspfuzz_mbox_restore_state(state.state.as_ptr()) },
+            DeviceKind::SmnFlash => unsafe {
+                aspfuzz_smn_flash_restore_state(state.state.as_ptr())
+            },
+        }
+        true
+    }
+
+    /// Puts the device into its power-on state
+    pub fn reset(&self) {
+        match self.kind {
+            DeviceKind::Timers => write_timers([0; 4]),
+            DeviceKind::SmnSlots => write_smn_slots([0; SMN_SLOTS]),
+            DeviceKind::Ccp => unsafe { aspfuzz_ccp_reset_state() },
+            DeviceKind::Mailbox => unsafe { aspfuzz_mbox_reset_state() },
+            DeviceKind::SmnFlash => unsafe { aspfuzz_smn_flash_reset_state() },
+        }
+    }
+}
+
+/// Little-endian 32-bit words in which `current` differs from `snapshot`,
+/// as offset, snapshot and current value
+pub fn diff_words(snapshot: &[u8], current: &[u8]) -> Vec<(usize, u32, u32)> {
+    let word = |bytes: &[u8]| {
+        let mut word = [0; 4];
+        word[..bytes.len()].copy_from_slice(bytes);
+        u32::from_le_bytes(word)
+    };
+    snapshot
+        .chunks(4)
+        .zip(current.chunks(4))
+        .enumerate()
+        .filter(|(_, (s, c))| s != c)
+        .map(|(i, (s, c))| (i * 4, word(s), word(c)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diverging_words() {
+        let snapshot = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0];
+        let current = [1, 0, 0, 0, 2, 0, 0, 0x80, 4, 0];
+        assert_eq!(
+            diff_words(&snapshot, &current),
+            vec![(4, 2, 0x8000_0002), (8, 3, 4)]
+        );
+        assert!(diff_words(&snapshot, &snapshot).is_empty());
+    }
+}
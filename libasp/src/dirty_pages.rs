@@ -0,0 +1,172 @@
+/// Tracking the SRAM pages written by a test-case, restored by `ResetLevel::DirtyPages`
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use libafl::inputs::UsesInput;
+use libafl_qemu::*;
+use sys::{GuestUsize, TCGTemp};
+
+use crate::{injecting, MemoryConfig};
+
+/// Granularity of the tracking, the target page size of QEMU for ARMv7
+pub const DIRTY_PAGE_SIZE: GuestAddr = 0x400;
+const BITMAP_WORDS: usize = 16;
+/// Largest SRAM that can be tracked
+pub const MAX_TRACKED_SIZE: GuestUsize = (BITMAP_WORDS * 64) as GuestUsize * DIRTY_PAGE_SIZE;
+
+static SRAM_START: AtomicU32 = AtomicU32::new(0);
+static SRAM_SIZE: AtomicU32 = AtomicU32::new(0);
+static DIRTY: [AtomicU64; BITMAP_WORDS] = [const { AtomicU64::new(0) }; BITMAP_WORDS];
+
+/// Sets the tracked SRAM and marks all of it clean
+pub fn track_dirty_pages(memory: &MemoryConfig) {
+    assert!(
+        memory.sram_size <= MAX_TRACKED_SIZE,
+        "SRAM of {:#x} bytes is too large to track dirty pages",
+        memory.sram_size
+    );
+    SRAM_START.store(memory.sram_start, Ordering::Relaxed);
+    SRAM_SIZE.store(memory.sram_size, Ordering::Relaxed);
+    clear_dirty_pages();
+}
+
+/// Marks the pages of a write of `size` bytes at `addr`, writes outside of the SRAM are ignored
+pub fn mark_dirty(addr: GuestAddr, size: usize) {
+    let start = SRAM_START.load(Ordering::Relaxed);
+    let sram_size = SRAM_SIZE.load(Ordering::Relaxed);
+    let Some(offset) = addr.checked_sub(start).filter(|offset| *offset < sram_size) else {
+        return;
+    };
+    let last = offset
+        .saturating_add(size.max(1) as GuestAddr - 1)
+        .min(sram_size - 1);
+    for page in offset / DIRTY_PAGE_SIZE..=last / DIRTY_PAGE_SIZE {
+        DIRTY[page as usize / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+    }
+}
+
+pub fn clear_dirty_pages() {
+    for word in &DIRTY {
+        word.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Offsets into the SRAM of the pages written since the last clear, adjacent pages are merged.
+/// The pages are marked clean.
+pub fn take_dirty_pages() -> Vec<Range<usize>> {
+    let sram_size = SRAM_SIZE.load(Ordering::Relaxed) as usize;
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, word) in DIRTY.iter().enumerate() {
+        let mut bits = word.swap(0, Ordering::Relaxed);
+        while bits != 0 {
+            let page = i * 64 + bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            let begin = page * DIRTY_PAGE_SIZE as usize;
+            let end = (begin + DIRTY_PAGE_SIZE as usize).min(sram_size);
+            match ranges.last_mut() {
+                Some(last) if last.end == begin => last.end = end,
+                _ => ranges.push(begin..end),
+            }
+        }
+    }
+    ranges
+}
+
+/// Installs the write hooks feeding the dirty page tracking.
+/// Writes by devices (e.g. CCP DMA) bypass them, a periodic full reset catches those.
+pub fn setup_dirty_page_hooks<QT, S>(hooks: &QemuHooks<QT, S>)
+where
+    QT: QemuHelperTuple<S>,
+    S: UsesInput,
+{
+    hooks.writes(
+        Hook::Function(gen_dirty_writes_hook),
+        Hook::Function(exec_dirty_writes_hook_1),
+        Hook::Function(exec_dirty_writes_hook_2),
+        Hook::Function(exec_dirty_writes_hook_4),
+        Hook::Function(exec_dirty_writes_hook_8),
+        Hook::Function(exec_dirty_writes_hook_n),
+    );
+}
+
+fn gen_dirty_writes_hook<QT, S>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _pc: GuestAddr,
+    _: *mut TCGTemp,
+    _mem_acces_info: MemAccessInfo,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: QemuHelperTuple<S>,
+{
+    // Injected instructions are not part of the guest
+    (!injecting()).then_some(0)
+}
+
+fn exec_dirty_writes_hook_1<QT: QemuHelperTuple<S>, S: UsesInput>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+) {
+    mark_dirty(addr, 1);
+}
+fn exec_dirty_writes_hook_2<QT: QemuHelperTuple<S>, S: UsesInput>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+) {
+    mark_dirty(addr, 2);
+}
+fn exec_dirty_writes_hook_4<QT: QemuHelperTuple<S>, S: UsesInput>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+) {
+    mark_dirty(addr, 4);
+}
+fn exec_dirty_writes_hook_8<QT: QemuHelperTuple<S>, S: UsesInput>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+) {
+    mark_dirty(addr, 8);
+}
+fn exec_dirty_writes_hook_n<QT: QemuHelperTuple<S>, S: UsesInput>(
+    _hooks: &mut QemuHooks<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+    size: usize,
+) {
+    mark_dirty(addr, size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_page_ranges() {
+        track_dirty_pages(&MemoryConfig {
+            sram_start: 0x1000,
+            sram_size: 0x2100,
+            lazy_size: 0x100,
+        });
+        mark_dirty(0x0, 4);
+        mark_dirty(0x1000, 4);
+        mark_dirty(0x13fe, 4);
+        mark_dirty(0x2000, 1);
+        mark_dirty(0x3000, 0x10);
+        mark_dirty(0x3100, 4);
+        assert_eq!(
+            take_dirty_pages(),
+            vec![0x0..0x800, 0x1000..0x1400, 0x2000..0x2100]
+        );
+        assert!(take_dirty_pages().is_empty());
+    }
+}
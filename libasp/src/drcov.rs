@@ -0,0 +1,209 @@
+/// The DrCov trace of the translated blocks and keeping it across resumed campaigns:
+/// a run rewrites `drcov.log` with its blocks, the blocks of the previous runs are merged back
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use libafl_qemu::GuestAddr;
+
+use crate::injecting;
+
+const BB_TABLE: &[u8] = b"BB Table: ";
+/// Start, size and module ID of a block
+const BB_ENTRY_SIZE: usize = 8;
+
+/// The single module of the trace, the PSP address space below the on-chip bootloader's end
+const DRCOV_MODULE: &str = "on-chip-ryzen-zen.bl";
+const DRCOV_MODULE_END: GuestAddr = 0xffff_9000;
+
+/// Where the trace is written, `None` until the harness start is reached
+static TRACE_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// Start and size of the translated blocks
+static BLOCKS: Mutex<BTreeMap<GuestAddr, u16>> = Mutex::new(BTreeMap::new());
+static NEW_BLOCKS: AtomicBool = AtomicBool::new(false);
+
+/// Starts tracing the blocks to `path`, the JIT has to be flushed so that
+/// the blocks translated before are recorded again
+pub fn start_drcov(path: PathBuf) {
+    *TRACE_PATH.lock().unwrap() = Some(path);
+}
+
+/// Whether a block translated at `pc` is traced
+pub fn traces_block(pc: GuestAddr) -> bool {
+    pc < DRCOV_MODULE_END && !injecting() && TRACE_PATH.lock().unwrap().is_some()
+}
+
+/// Records a translated block, called from the block hooks
+pub fn record_block(pc: GuestAddr, size: u64) {
+    if !traces_block(pc) {
+        return;
+    }
+    let size = size.min(u64::from(u16::MAX)) as u16;
+    if BLOCKS.lock().unwrap().insert(pc, size) != Some(size) {
+        NEW_BLOCKS.store(true, Ordering::Relaxed);
+    }
+}
+
+fn drcov_file(blocks: &BTreeMap<GuestAddr, u16>) -> Vec<u8> {
+    let mut drcov = format!(
+        "DRCOV VERSION: 2\nDRCOV FLAVOR: libafl\n\
+        Module Table: version 2, count 1\n\
+        Columns: id, base, end, entry, checksum, timestamp, path\n\
+        000, 0x0, {DRCOV_MODULE_END:#x}, 0x0, 0x0, 0x0, {DRCOV_MODULE}\n\
+        BB Table: {} bbs\n",
+        blocks.len()
+    )
+    .into_bytes();
+    for (start, size) in blocks {
+        drcov.extend_from_slice(&start.to_le_bytes());
+        drcov.extend_from_slice(&size.to_le_bytes());
+        drcov.extend_from_slice(&0u16.to_le_bytes());
+    }
+    drcov
+}
+
+/// Merges the blocks recorded since the last call into the trace,
+/// the clients of a campaign share it
+pub fn flush_drcov() {
+    if !NEW_BLOCKS.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let Some(path) = TRACE_PATH.lock().unwrap().clone() else {
+        return;
+    };
+    let trace = drcov_file(&BLOCKS.lock().unwrap());
+    let trace = match fs::read(&path) {
+        Ok(previous) => merge_drcov(&previous, &trace),
+        Err(_) => trace,
+    };
+    fs::write(path, trace).unwrap();
+}
+
+/// Splits a DrCov file into the text before the block table and the block entries
+fn split_blocks(drcov: &[u8]) -> Option<(&[u8], &[u8])> {
+    let table = drcov.windows(BB_TABLE.len()).position(|w| w == BB_TABLE)?;
+    let entries = table + drcov[table..].iter().position(|&b| b == b'\n')? + 1;
+    Some((&drcov[..table], &drcov[entries..]))
+}
+
+/// `current` with the blocks of `previous` it does not have yet.
+/// The module table is taken from `current`.
+pub fn merge_drcov(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    let Some((header, current_blocks)) = split_blocks(current) else {
+        return previous.to_vec();
+    };
+    let Some((_, previous_blocks)) = split_blocks(previous) else {
+        return current.to_vec();
+    };
+    let mut blocks: Vec<&[u8]> = current_blocks.chunks_exact(BB_ENTRY_SIZE).collect();
+    for block in previous_blocks.chunks_exact(BB_ENTRY_SIZE) {
+        if !blocks.contains(&block) {
+            blocks.push(block);
+        }
+    }
+    let mut merged = header.to_vec();
+    merged.extend_from_slice(format!("BB Table: {} bbs\n", blocks.len()).as_bytes());
+    for block in blocks {
+        merged.extend_from_slice(block);
+    }
+    merged
+}
+
+fn previous_path(path: &Path) -> PathBuf {
+    path.with_extension("log.previous")
+}
+
+/// Moves the trace of the previous runs to `{log}.previous` before a resumed run,
+/// a trace left there by an interrupted run is merged with it
+pub fn set_aside_drcov(path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let previous = previous_path(path);
+    let trace = if previous.exists() {
+        merge_drcov(&fs::read(&previous).unwrap(), &fs::read(path).unwrap())
+    } else {
+        fs::read(path).unwrap()
+    };
+    fs::write(&previous, trace).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+/// Appends the trace of the previous runs to the one of the current run
+pub fn merge_back_drcov(path: &Path) {
+    let previous = previous_path(path);
+    if !previous.exists() {
+        return;
+    }
+    let previous_trace = fs::read(&previous).unwrap();
+    let trace = if path.exists() {
+        merge_drcov(&previous_trace, &fs::read(path).unwrap())
+    } else {
+        previous_trace
+    };
+    fs::write(path, trace).unwrap();
+    fs::remove_file(previous).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drcov(blocks: &[(u32, u16)]) -> Vec<u8> {
+        let mut drcov = b"DRCOV VERSION: 2\nDRCOV FLAVOR: libafl\n\
+            Module Table: version 2, count 1\n\
+            Columns: id, base, end, entry, checksum, timestamp, path\n\
+            000, 0x0, 0xffff9000, 0x0, 0x0, 0x0, on-chip-ryzen-zen.bl\n"
+            .to_vec();
+        drcov.extend_from_slice(format!("BB Table: {} bbs\n", blocks.len()).as_bytes());
+        for (start, size) in blocks {
+            drcov.extend_from_slice(&start.to_le_bytes());
+            drcov.extend_from_slice(&size.to_le_bytes());
+            drcov.extend_from_slice(&0u16.to_le_bytes());
+        }
+        drcov
+    }
+
+    #[test]
+    fn written_blocks() {
+        let blocks = BTreeMap::from([(0x100, 8), (0x200, 4)]);
+        assert_eq!(drcov_file(&blocks), drcov(&[(0x100, 8), (0x200, 4)]));
+    }
+
+    #[test]
+    fn merged_blocks() {
+        let previous = drcov(&[(0x100, 8), (0x200, 4)]);
+        let current = drcov(&[(0x200, 4), (0x300, 0x10)]);
+        assert_eq!(
+            merge_drcov(&previous, &current),
+            drcov(&[(0x200, 4), (0x300, 0x10), (0x100, 8)])
+        );
+        assert_eq!(merge_drcov(&previous, b""), previous);
+        assert_eq!(merge_drcov(b"", &current), current);
+    }
+
+    #[test]
+    fn interrupted_resume() {
+        let dir = std::env::temp_dir().join(format!("drcov-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("drcov.log");
+        fs::write(&path, drcov(&[(0x100, 8)])).unwrap();
+
+        // The resumed run is killed before it merges the trace back
+        set_aside_drcov(&path);
+        fs::write(&path, drcov(&[(0x200, 4)])).unwrap();
+        set_aside_drcov(&path);
+        assert!(!path.exists());
+
+        fs::write(&path, drcov(&[(0x300, 4)])).unwrap();
+        merge_back_drcov(&path);
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            drcov(&[(0x300, 4), (0x200, 4), (0x100, 8)])
+        );
+        assert!(!previous_path(&path).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,12 +3,17 @@ use libafl::prelude::*;
 use libafl_qemu::*;
 
 use core::fmt::Debug;
-use libafl_bolts::Named;
+use libafl_bolts::{impl_serdeany, Named};
 use log;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Mutex, OnceLock,
+};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExceptionType {
     RESET = 0,
     UNDEF = 1,
@@ -37,25 +42,34 @@ impl From<u32> for ExceptionType {
     }
 }
 
-// TODO make this depedent on machine register
-// for exception handler base
-pub const ON_CHIP_ADDR: GuestAddr = 0x100;
+/// Vector base when SCTLR.V selects the high vectors
+pub const HIGH_VECTORS_ADDR: GuestAddr = 0xffff_0000;
+/// SCTLR.V
+const SCTLR_V: u32 = 1 << 13;
 
+/// Exception types that are hooked in the vector table
+const HOOKED_EXCEPTIONS: [ExceptionType; 7] = [
+    ExceptionType::UNDEF,
+    ExceptionType::SVC,
+    ExceptionType::PREAB,
+    ExceptionType::DATAB,
+    ExceptionType::HYP,
+    ExceptionType::IRQ,
+    ExceptionType::FIQ,
+];
+
+#[derive(Clone)]
 pub struct ExceptionHandler {
     exception_vector_base: GuestAddr,
-    #[allow(dead_code)]
-    exception_addr_reset: GuestAddr,
-    exception_addr_undef: GuestAddr,
-    exception_addr_svc: GuestAddr,
-    exception_addr_preab: GuestAddr,
-    exception_addr_datab: GuestAddr,
-    exception_addr_hyp: GuestAddr,
-    exception_addr_irq: GuestAddr,
-    exception_addr_fiq: GuestAddr,
     hook_ids: Vec<InstructionHookId>,
 }
 
-static mut EXCEPTION_VECTOR_BASE: GuestAddr = 0;
+/// Vector base the hooks are currently armed for
+static EXCEPTION_VECTOR_BASE: AtomicU32 = AtomicU32::new(0);
+static ARMED: AtomicBool = AtomicBool::new(false);
+static REARM_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Handed to the exception hooks, needs a stable address
+static EMULATOR: OnceLock<Qemu> = OnceLock::new();
 
 impl Default for ExceptionHandler {
     fn default() -> Self {
@@ -65,120 +79,567 @@ impl Default for ExceptionHandler {
 
 impl ExceptionHandler {
     pub fn new() -> Self {
-        let exception_vector_base = ON_CHIP_ADDR;
         Self {
-            exception_vector_base,
-            exception_addr_reset: exception_vector_base + 4 * (ExceptionType::RESET as u32),
-            exception_addr_undef: exception_vector_base + 4 * (ExceptionType::UNDEF as u32),
-            exception_addr_svc: exception_vector_base + 4 * (ExceptionType::SVC as u32),
-            exception_addr_preab: exception_vector_base + 4 * (ExceptionType::PREAB as u32),
-            exception_addr_datab: exception_vector_base + 4 * (ExceptionType::DATAB as u32),
-            exception_addr_hyp: exception_vector_base + 4 * (ExceptionType::HYP as u32),
-            exception_addr_irq: exception_vector_base + 4 * (ExceptionType::IRQ as u32),
-            exception_addr_fiq: exception_vector_base + 4 * (ExceptionType::FIQ as u32),
+            exception_vector_base: 0,
             hook_ids: vec![],
         }
     }
+    /// Vector base as configured by SCTLR.V and VBAR, as of the last
+    /// [`read_vector_registers`] or observed `MCR`
+    pub fn current_vector_base() -> GuestAddr {
+        if SCTLR.load(Ordering::Relaxed) & SCTLR_V != 0 {
+            HIGH_VECTORS_ADDR
+        } else {
+            VBAR.load(Ordering::Relaxed) & !0x1f
+        }
+    }
     pub fn is_exception_handler_addr(addr: &GuestAddr) -> bool {
-        (ON_CHIP_ADDR..(ON_CHIP_ADDR + 4 * ExceptionType::UNKNOWN as u32)).contains(addr)
+        let base = EXCEPTION_VECTOR_BASE.load(Ordering::Relaxed);
+        (base..(base + 4 * ExceptionType::UNKNOWN as u32)).contains(addr)
+    }
+    /// Exception type belonging to an address inside the exception vector table
+    pub fn exception_type(addr: &GuestAddr) -> Option<ExceptionType> {
+        if Self::is_exception_handler_addr(addr) {
+            Some(((addr - EXCEPTION_VECTOR_BASE.load(Ordering::Relaxed)) / 4).into())
+        } else {
+            None
+        }
     }
     pub fn start(&mut self, emu: &Qemu) {
-        unsafe { EXCEPTION_VECTOR_BASE = self.exception_vector_base };
+        read_vector_registers(emu);
+        self.arm(emu);
+    }
+
+    fn arm(&mut self, emu: &Qemu) {
+        self.exception_vector_base = Self::current_vector_base();
+        log::debug!(
+            "Exception vector base at {:#010x}",
+            self.exception_vector_base
+        );
+        EXCEPTION_VECTOR_BASE.store(self.exception_vector_base, Ordering::Relaxed);
+        let emu = EMULATOR.get_or_init(|| *emu);
         //emu.set_hook(self.exception_addr_reset, exception_hook, emu as *const _ as u64, false);
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_undef,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_svc,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_preab,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_datab,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_hyp,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_irq,
-            exception_hook,
-            false,
-        ));
-        self.hook_ids.push(emu.set_hook(
-            emu as *const _ as u64,
-            self.exception_addr_fiq,
-            exception_hook,
-            false,
-        ));
-    }
-
-    pub fn stop(&self) {
-        for &hook_id in &self.hook_ids {
+        for exception in HOOKED_EXCEPTIONS {
+            self.hook_ids.push(emu.set_hook(
+                emu as *const _ as u64,
+                self.exception_vector_base + 4 * (exception as u32),
+                exception_hook,
+                false,
+            ));
+        }
+        ARMED.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stop(&mut self) {
+        ARMED.store(false, Ordering::Relaxed);
+        for hook_id in self.hook_ids.drain(..) {
             hook_id.remove(true);
         }
     }
+
+    /// Moves the hooks if the guest relocated its exception vectors, either
+    /// with the `MCR` the emulator stopped in front of or since the last check.
+    /// Returns whether the hooks had to be moved.
+    pub fn rearm(&mut self, emu: &Qemu) -> bool {
+        // In front of the `MCR` the CPU still has the old value
+        if !REARM_REQUESTED.swap(false, Ordering::Relaxed) {
+            read_vector_registers(emu);
+        }
+        if Self::current_vector_base() == self.exception_vector_base {
+            return false;
+        }
+        self.stop();
+        self.arm(emu);
+        true
+    }
+
+    /// Hooks the `MCR`s to SCTLR and VBAR in the code of a block, meant to be
+    /// called from a block generation hook. A hooked `MCR` moving the vectors stops
+    /// the execution, so that [`ExceptionHandler::rearm`] can follow the guest.
+    pub fn watch_vector_writes(emu: &Qemu, block: GuestAddr) {
+        let emu = EMULATOR.get_or_init(|| *emu);
+        // A block does not cross its page
+        let mut code =
+            vec![0; (VECTOR_WRITE_SCAN_SIZE - (block % VECTOR_WRITE_SCAN_SIZE)) as usize];
+        unsafe { emu.read_mem(block, &mut code) };
+        let mut watched = WATCHED_VECTOR_WRITES.lock().unwrap();
+        for (offset, write) in find_vector_writes(&code) {
+            let addr = block + offset as GuestAddr;
+            if watched.insert(addr) {
+                log::debug!("Watching {:?} at {:#x}", write, addr);
+                emu.set_hook(write.encode(), addr, vector_write_hook, false);
+            }
+        }
+    }
+
+    /// Whether the last stop of the emulator was caused by a hooked `MCR` moving the vectors
+    pub fn rearm_requested() -> bool {
+        REARM_REQUESTED.load(Ordering::Relaxed)
+    }
+
+    /// Adds the SP and LR of the interrupted mode and the fault status and
+    /// address registers to the exception the emulator stopped at
+    pub fn read_fault_registers(emu: &Qemu) {
+        let Some(exception) = TRIGGERED.lock().unwrap().as_ref().map(|m| m.exception) else {
+            return;
+        };
+        let (sp, lr) = read_interrupted_sp_lr(emu);
+        if let Some(metadata) = TRIGGERED.lock().unwrap().as_mut() {
+            metadata.interrupted_sp = Some(sp);
+            metadata.interrupted_lr = Some(lr);
+        }
+        let regs = match exception {
+            ExceptionType::PREAB => [Cp15Reg::Ifar, Cp15Reg::Ifsr],
+            ExceptionType::DATAB => [Cp15Reg::Dfar, Cp15Reg::Dfsr],
+            _ => return,
+        };
+        let values = read_cp15(emu, &regs);
+        if let Some(metadata) = TRIGGERED.lock().unwrap().as_mut() {
+            match exception {
+                ExceptionType::PREAB => {
+                    metadata.ifar = Some(values[0]);
+                    metadata.ifsr = Some(values[1]);
+                }
+                _ => {
+                    metadata.dfar = Some(values[0]);
+                    metadata.dfsr = Some(values[1]);
+                }
+            }
+        }
+    }
+}
+
+/// Last known guest values of SCTLR and VBAR
+static SCTLR: AtomicU32 = AtomicU32::new(0);
+static VBAR: AtomicU32 = AtomicU32::new(0);
+/// Addresses of the hooked `MCR`s
+static WATCHED_VECTOR_WRITES: Mutex<BTreeSet<GuestAddr>> = Mutex::new(BTreeSet::new());
+/// Code after a block start searched for `MCR`s, up to the end of the page
+const VECTOR_WRITE_SCAN_SIZE: GuestAddr = 0x1000;
+/// Set while [`inject`] runs its instructions, the hooks ignore them
+static INJECTING: AtomicBool = AtomicBool::new(false);
+
+/// Whether [`inject`] runs its instructions, the block and write hooks return early then
+pub fn injecting() -> bool {
+    INJECTING.load(Ordering::Relaxed)
+}
+
+/// CPSR bits
+const CPSR_T: u32 = 1 << 5;
+const CPSR_F: u32 = 1 << 6;
+const CPSR_I: u32 = 1 << 7;
+const CPSR_MODE_USR: u32 = 0x10;
+
+/// Reads SCTLR and VBAR of the guest
+fn read_vector_registers(emu: &Qemu) {
+    let values = read_cp15(emu, &[Cp15Reg::Sctlr, Cp15Reg::Vbar]);
+    SCTLR.store(values[0], Ordering::Relaxed);
+    VBAR.store(values[1], Ordering::Relaxed);
+}
+
+/// CP15 registers describing the exception configuration, exceptions and the MMU
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cp15Reg {
+    Sctlr,
+    Vbar,
+    Dfsr,
+    Ifsr,
+    Dfar,
+    Ifar,
+    Ttbr0,
+    Ttbr1,
+    Ttbcr,
+    Dacr,
+}
+
+impl Cp15Reg {
+    /// (CRn, opc1, CRm, opc2) encoding of the register
+    fn encoding(&self) -> (u32, u32, u32, u32) {
+        match self {
+            Cp15Reg::Sctlr => (1, 0, 0, 0),
+            Cp15Reg::Vbar => (12, 0, 0, 0),
+            Cp15Reg::Dfsr => (5, 0, 0, 0),
+            Cp15Reg::Ifsr => (5, 0, 0, 1),
+            Cp15Reg::Dfar => (6, 0, 0, 0),
+            Cp15Reg::Ifar => (6, 0, 0, 2),
+            Cp15Reg::Ttbr0 => (2, 0, 0, 0),
+            Cp15Reg::Ttbr1 => (2, 0, 0, 1),
+            Cp15Reg::Ttbcr => (2, 0, 0, 2),
+            Cp15Reg::Dacr => (3, 0, 0, 0),
+        }
+    }
+
+    /// `MRC p15, opc1, Rt, CRn, CRm, opc2` in the A32 encoding
+    fn mrc(&self, rt: u32) -> u32 {
+        let (crn, opc1, crm, opc2) = self.encoding();
+        0xee10_0f10 | opc1 << 21 | crn << 16 | rt << 12 | opc2 << 5 | crm
+    }
+
+    /// `MCR p15, opc1, Rt, CRn, CRm, opc2` in the A32 encoding
+    fn mcr(&self, rt: u32) -> u32 {
+        self.mrc(rt) & !(1 << 20)
+    }
+}
+
+/// Runs the A32 `code` in place of the code at the current PC with R0.. set to `inputs`
+/// and returns `outputs` registers from R0 on, only while the emulator is stopped in a
+/// privileged mode. The registers, CPSR, PC and the code are restored afterwards.
+/// QEMU neither exports CP15 nor the banked registers in its register API.
+fn inject(emu: &Qemu, code: &[u32], inputs: &[u32], outputs: usize) -> Vec<u32> {
+    const SCRATCH: [Regs; 8] = [
+        Regs::R0,
+        Regs::R1,
+        Regs::R2,
+        Regs::R3,
+        Regs::R4,
+        Regs::R5,
+        Regs::R6,
+        Regs::R7,
+    ];
+    let used = inputs.len().max(outputs);
+    assert!(used <= SCRATCH.len(), "Too many registers to inject");
+    let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+    let pc: GuestAddr = cpu.read_reg(Regs::Pc).unwrap();
+    let cpsr: u32 = cpu.read_reg(Regs::Cpsr).unwrap();
+    assert_ne!(
+        cpsr & 0x1f,
+        CPSR_MODE_USR,
+        "Cannot inject instructions in user mode"
+    );
+    let saved: Vec<u32> = SCRATCH[..used]
+        .iter()
+        .map(|&r| cpu.read_reg(r).unwrap())
+        .collect();
+    let mut original = vec![0; 4 * code.len()];
+    unsafe { cpu.read_mem(pc, &mut original) };
+
+    let injected: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    let end = pc + original.len() as GuestAddr;
+    INJECTING.store(true, Ordering::Relaxed);
+    for (&r, &value) in SCRATCH.iter().zip(inputs) {
+        cpu.write_reg(r, value).unwrap();
+    }
+    unsafe { cpu.write_mem(pc, &injected) };
+    // A32 with interrupts masked
+    cpu.write_reg(Regs::Cpsr, (cpsr & !CPSR_T) | CPSR_I | CPSR_F)
+        .unwrap();
+    emu.set_breakpoint(end);
+    match unsafe { emu.run() } {
+        Ok(QemuExitReason::Breakpoint(addr)) if addr == end => {}
+        other => panic!("Injected instructions at {pc:#x} stopped with {other:?}"),
+    }
+    emu.remove_breakpoint(end);
+    let values = SCRATCH[..outputs]
+        .iter()
+        .map(|&r| cpu.read_reg(r).unwrap())
+        .collect();
+
+    unsafe { cpu.write_mem(pc, &original) };
+    for (&r, &value) in SCRATCH.iter().zip(&saved) {
+        cpu.write_reg(r, value).unwrap();
+    }
+    // Switches back to the banked registers of the original mode
+    cpu.write_reg(Regs::Cpsr, cpsr).unwrap();
+    cpu.write_reg(Regs::Pc, pc).unwrap();
+    INJECTING.store(false, Ordering::Relaxed);
+    values
+}
+
+/// Reads CP15 registers with `MRC`s, see [`inject`]
+pub fn read_cp15(emu: &Qemu, regs: &[Cp15Reg]) -> Vec<u32> {
+    let mrcs: Vec<u32> = regs
+        .iter()
+        .enumerate()
+        .map(|(rt, reg)| reg.mrc(rt as u32))
+        .collect();
+    inject(emu, &mrcs, &[], regs.len())
+}
+
+/// Writes CP15 registers with `MCR`s in the given order, see [`inject`]
+pub fn write_cp15(emu: &Qemu, values: &[(Cp15Reg, u32)]) {
+    let mcrs: Vec<u32> = values
+        .iter()
+        .enumerate()
+        .map(|(rt, (reg, _))| reg.mcr(rt as u32))
+        .collect();
+    let inputs: Vec<u32> = values.iter().map(|(_, value)| *value).collect();
+    inject(emu, &mcrs, &inputs, 0);
+}
+
+/// Privileged modes with banked registers: FIQ, IRQ, supervisor, abort and undefined
+const BANKED_MODES: [u32; 5] = [0x11, 0x12, 0x13, 0x17, 0x1b];
+const CPSR_MODE_FIQ: u32 = 0x11;
+const CPSR_MODE_SYS: u32 = 0x1f;
+
+/// `CPS #mode`
+fn cps(mode: u32) -> u32 {
+    0xf102_0000 | mode
+}
+
+/// `MOV Rd, Rm`
+fn mov(rd: u32, rm: u32) -> u32 {
+    0xe1a0_0000 | rd << 12 | rm
+}
+
+/// Banked registers of `mode` in R0..: SP, LR, SPSR and R8-R12 of the FIQ mode
+fn banked_count(mode: u32) -> usize {
+    if mode == CPSR_MODE_FIQ {
+        8
+    } else {
+        3
+    }
+}
+
+/// The banked registers of all privileged modes, see [`banked_count`] for their order
+pub fn read_banked(emu: &Qemu) -> Vec<u32> {
+    let mut values = Vec::new();
+    for mode in BANKED_MODES {
+        // mrs r2, spsr
+        let mut code = vec![cps(mode), mov(0, 13), mov(1, 14), 0xe14f_2000];
+        code.extend((8..banked_count(mode) as u32 + 5).map(|r| mov(r - 5, r)));
+        values.extend(inject(emu, &code, &[], banked_count(mode)));
+    }
+    values
+}
+
+/// SP and LR of the mode the current exception mode interrupted, taken from its SPSR
+fn read_interrupted_sp_lr(emu: &Qemu) -> (GuestAddr, GuestAddr) {
+    // mrs r0, spsr
+    let spsr = inject(emu, &[0xe14f_0000], &[], 1)[0];
+    // The system mode shares SP and LR with the user mode, `CPS` cannot switch to the latter
+    let mode = match spsr & 0x1f {
+        CPSR_MODE_USR => CPSR_MODE_SYS,
+        mode => mode,
+    };
+    let values = inject(emu, &[cps(mode), mov(0, 13), mov(1, 14)], &[], 2);
+    (values[0], values[1])
+}
+
+/// Writes the banked registers read by [`read_banked`]
+pub fn write_banked(emu: &Qemu, values: &[u32]) {
+    let mut values = values;
+    for mode in BANKED_MODES {
+        let (mode_values, rest) = values.split_at(banked_count(mode));
+        // msr spsr_fsxc, r2
+        let mut code = vec![cps(mode), mov(13, 0), mov(14, 1), 0xe16f_f002];
+        code.extend((8..banked_count(mode) as u32 + 5).map(|r| mov(r, r - 5)));
+        inject(emu, &code, mode_values, 0);
+        values = rest;
+    }
+}
+
+/// An `MCR` to SCTLR or VBAR
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct VectorWrite {
+    reg: Cp15Reg,
+    /// Source register
+    rt: u32,
+    /// Condition of an A32 `MCR`
+    cond: u32,
+    thumb: bool,
+}
+
+impl VectorWrite {
+    fn decode(insn: u32, thumb: bool) -> Option<Self> {
+        let cond = insn >> 28;
+        if (thumb && cond != 0xe) || cond == 0xf {
+            return None;
+        }
+        let reg = match insn & 0x0fff_0fff {
+            0x0e01_0f10 => Cp15Reg::Sctlr,
+            0x0e0c_0f10 => Cp15Reg::Vbar,
+            _ => return None,
+        };
+        Some(Self {
+            reg,
+            rt: (insn >> 12) & 0xf,
+            cond,
+            thumb,
+        })
+    }
+
+    /// Hook data
+    fn encode(&self) -> u64 {
+        u64::from(self.reg == Cp15Reg::Vbar)
+            | u64::from(self.rt) << 4
+            | u64::from(self.cond) << 8
+            | u64::from(self.thumb) << 12
+    }
+
+    fn from_data(data: u64) -> Self {
+        Self {
+            reg: if data & 1 != 0 {
+                Cp15Reg::Vbar
+            } else {
+                Cp15Reg::Sctlr
+            },
+            rt: ((data >> 4) & 0xf) as u32,
+            cond: ((data >> 8) & 0xf) as u32,
+            thumb: data & (1 << 12) != 0,
+        }
+    }
+}
+
+/// `MCR`s to SCTLR or VBAR in A32 or T32 code, with their offset in `code`
+fn find_vector_writes(code: &[u8]) -> Vec<(usize, VectorWrite)> {
+    let halfword = |i: usize| u32::from(u16::from_le_bytes([code[i], code[i + 1]]));
+    let mut writes = Vec::new();
+    for offset in (0..code.len().saturating_sub(3)).step_by(2) {
+        if offset % 4 == 0 {
+            let insn = u32::from_le_bytes(code[offset..offset + 4].try_into().unwrap());
+            if let Some(write) = VectorWrite::decode(insn, false) {
+                writes.push((offset, write));
+                continue;
+            }
+        }
+        // T32 is stored as two halfwords, the first one holding the opcode
+        if let Some(write) =
+            VectorWrite::decode(halfword(offset) << 16 | halfword(offset + 2), true)
+        {
+            writes.push((offset, write));
+        }
+    }
+    writes
+}
+
+/// Whether an A32 condition holds for the flags in `cpsr`
+fn condition_passed(cond: u32, cpsr: u32) -> bool {
+    let (n, z, c, v) = (
+        cpsr & (1 << 31) != 0,
+        cpsr & (1 << 30) != 0,
+        cpsr & (1 << 29) != 0,
+        cpsr & (1 << 28) != 0,
+    );
+    let passed = match cond >> 1 {
+        0 => z,
+        1 => c,
+        2 => n,
+        3 => v,
+        4 => c && !z,
+        5 => n == v,
+        6 => !z && n == v,
+        _ => return true,
+    };
+    passed != (cond & 1 != 0)
+}
+
+extern "C" fn vector_write_hook(data: u64, pc: GuestAddr) {
+    if INJECTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let write = VectorWrite::from_data(data);
+    let emu = EMULATOR.get().unwrap();
+    let cpsr: u32 = emu.read_reg(Regs::Cpsr).unwrap();
+    if (cpsr & CPSR_T != 0) != write.thumb || !condition_passed(write.cond, cpsr) {
+        return;
+    }
+    let value: u32 = emu.read_reg(write.rt as i32).unwrap();
+    log::debug!("{:?} = {:#x} at {:#x}", write.reg, value, pc);
+    match write.reg {
+        Cp15Reg::Sctlr => SCTLR.store(value, Ordering::Relaxed),
+        _ => VBAR.store(value, Ordering::Relaxed),
+    }
+    if ARMED.load(Ordering::Relaxed)
+        && ExceptionHandler::current_vector_base() != EXCEPTION_VECTOR_BASE.load(Ordering::Relaxed)
+    {
+        REARM_REQUESTED.store(true, Ordering::Relaxed);
+        emu.current_cpu().unwrap().trigger_breakpoint();
+    }
+}
+
+/// Information about the exception that ended a test-case
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExceptionMetadata {
+    pub exception: ExceptionType,
+    /// Banked LR of the exception mode
+    pub lr: GuestAddr,
+    /// Instruction causing a data or prefetch abort
+    pub fault_pc: Option<GuestAddr>,
+    /// SP and LR of the mode the exception interrupted
+    pub interrupted_sp: Option<GuestAddr>,
+    pub interrupted_lr: Option<GuestAddr>,
+    pub dfar: Option<u32>,
+    pub dfsr: Option<u32>,
+    pub ifar: Option<u32>,
+    pub ifsr: Option<u32>,
+}
+
+impl_serdeany!(ExceptionMetadata);
+
+impl ExceptionMetadata {
+    fn new(exception: ExceptionType, lr: GuestAddr) -> Self {
+        Self {
+            exception,
+            lr,
+            fault_pc: None,
+            interrupted_sp: None,
+            interrupted_lr: None,
+            dfar: None,
+            dfsr: None,
+            ifar: None,
+            ifsr: None,
+        }
+    }
 }
 
 extern "C" fn exception_hook(data: u64, pc: GuestAddr) {
-    match ((pc - unsafe { EXCEPTION_VECTOR_BASE }) / 4).into() {
-        ExceptionType::RESET => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::RESET as u8 },
-        ExceptionType::UNDEF => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::UNDEF as u8 },
-        // ExceptionType::SVC => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::SVC as u8 },
-        ExceptionType::SVC => return,
-        ExceptionType::PREAB => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::PREAB as u8 },
-        ExceptionType::DATAB => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::DATAB as u8 },
-        //ExceptionType::DATAB => log::info!("Data abort triggered"),
-        ExceptionType::HYP => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::HYP as u8 },
-        ExceptionType::IRQ => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::IRQ as u8 },
-        ExceptionType::FIQ => unsafe { HOOK_TRIGGERED |= 1 << ExceptionType::FIQ as u8 },
-        _ => log::error!("Unknown exception triggered"),
+    if INJECTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let exception: ExceptionType =
+        ((pc - EXCEPTION_VECTOR_BASE.load(Ordering::Relaxed)) / 4).into();
+    if let ExceptionType::SVC = exception {
+        return;
     }
     let emu = unsafe { (data as *const Qemu).as_ref().unwrap() };
     log::debug!("Exception hook: pc={:#x}", pc);
-    match ((pc - unsafe { EXCEPTION_VECTOR_BASE }) / 4).into() {
-        ExceptionType::RESET => log::debug!("Exception: RESET"),
-        ExceptionType::UNDEF => {
-            let sp: u32 = emu.read_reg(Regs::Sp).unwrap();
-            let lr: u32 = emu.read_reg(Regs::Lr).unwrap();
-            log::debug!("Exception: UNDEF sp_undef: {sp:#x} lr_undef: {lr:#x}");
-        }
-        ExceptionType::SVC => log::debug!("Exception: SVC"),
-        ExceptionType::PREAB => log::debug!("Exception: PREAB"),
-        ExceptionType::DATAB => {
-            let sp: u32 = emu.read_reg(Regs::Sp).unwrap();
-            let lr: u32 = emu.read_reg(Regs::Lr).unwrap();
-            log::debug!("Exception: DATAAB sp_undef: {sp:#x} lr_undef: {lr:#x}");
-        }
-        ExceptionType::HYP => log::debug!("Exception: HYP"),
-        ExceptionType::IRQ => log::debug!("Exception: IRQ"),
-        ExceptionType::FIQ => log::debug!("Exception: FIQ"),
-        _ => log::error!("Unknown exception triggered"),
+
+    // We are at the vector entry, so SP and LR are banked for the exception mode
+    let sp: u32 = emu.read_reg(Regs::Sp).unwrap();
+    let lr: u32 = emu.read_reg(Regs::Lr).unwrap();
+    let mut metadata = ExceptionMetadata::new(exception, lr);
+    match exception {
+        // The fault registers are read once the emulator stopped
+        ExceptionType::PREAB => metadata.fault_pc = Some(lr.wrapping_sub(4)),
+        ExceptionType::DATAB => metadata.fault_pc = Some(lr.wrapping_sub(8)),
+        ExceptionType::UNKNOWN => log::error!("Unknown exception triggered"),
+        _ => {}
     }
+    log::debug!("Exception: {exception:?} sp: {sp:#x} lr: {lr:#x}");
+    log::debug!("{metadata:?}");
+
+    // Only the first exception of a test-case is of interest
+    TRIGGERED.lock().unwrap().get_or_insert(metadata);
 
     emu.current_cpu().unwrap().trigger_breakpoint();
 }
 
-static mut HOOK_TRIGGERED: usize = 0;
+/// Exception that was triggered during the current test-case
+static TRIGGERED: Mutex<Option<ExceptionMetadata>> = Mutex::new(None);
+
+/// Takes the exception that was triggered during the current test-case
+pub fn take_triggered_exception() -> Option<ExceptionMetadata> {
+    TRIGGERED.lock().unwrap().take()
+}
+
+/// The exception that was triggered during the current test-case, read by the
+/// objectives; the next execution clears it
+pub fn triggered_exception() -> Option<ExceptionMetadata> {
+    TRIGGERED.lock().unwrap().clone()
+}
+
+/// Drops an exception left over from an execution whose metadata was not taken,
+/// e.g. during calibration, tracing or generalization
+pub fn clear_triggered_exception() {
+    if let Some(stale) = TRIGGERED.lock().unwrap().take() {
+        log::debug!("Dropping stale exception {:?}", stale.exception);
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ExceptionFeedback {}
+pub struct ExceptionFeedback {
+    last: Option<ExceptionMetadata>,
+}
 
 impl<S> Feedback<S> for ExceptionFeedback
 where
@@ -197,16 +658,33 @@ where
         EM: EventFirer,
         OT: ObserversTuple<S>,
     {
-        unsafe {
-            if HOOK_TRIGGERED != 0 {
-                log::info!("ExceptionFeedback=True");
-                HOOK_TRIGGERED = 0;
+        self.last = triggered_exception();
+        match &self.last {
+            Some(metadata) => {
+                log::info!("ExceptionFeedback=True ({:?})", metadata.exception);
                 Ok(true)
-            } else {
-                Ok(false)
             }
+            None => Ok(false),
         }
     }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _em: &mut EM,
+        _ot: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error> {
+        if let Some(metadata) = self.last.take() {
+            testcase.add_metadata(metadata);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.last = None;
+        Ok(())
+    }
 }
 
 impl Named for ExceptionFeedback {
@@ -220,7 +698,7 @@ impl ExceptionFeedback {
     /// Creates a new [`ExceptionFeedback`]
     #[must_use]
     pub fn new() -> Self {
-        Self {}
+        Self { last: None }
     }
 }
 
@@ -229,3 +707,48 @@ impl Default for ExceptionFeedback {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vector_writes() {
+        assert_eq!(Cp15Reg::Sctlr.mrc(0), 0xee11_0f10);
+        assert_eq!(Cp15Reg::Ifar.mrc(1), 0xee16_1f50);
+        assert_eq!(Cp15Reg::Ttbr0.mcr(1), 0xee02_1f10);
+
+        // mov r0, r0; mcrne p15, 0, r1, c1, c0, 0; mcr p15, 0, r3, c12, c0, 0;
+        // T32 mcr p15, 0, r2, c12, c0, 0; mrc p15, 0, r0, c1, c0, 0
+        let mut code = Vec::new();
+        for insn in [0xe1a0_0000u32, 0x1e01_1f10, 0xee0c_3f10] {
+            code.extend_from_slice(&insn.to_le_bytes());
+        }
+        for halfword in [0xee0cu16, 0x2f10] {
+            code.extend_from_slice(&halfword.to_le_bytes());
+        }
+        code.extend_from_slice(&0xee11_0f10u32.to_le_bytes());
+        let writes = find_vector_writes(&code);
+        let found: Vec<_> = writes
+            .iter()
+            .map(|(offset, w)| (*offset, w.reg, w.rt, w.thumb))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (4, Cp15Reg::Sctlr, 1, false),
+                (8, Cp15Reg::Vbar, 3, false),
+                (12, Cp15Reg::Vbar, 2, true),
+            ]
+        );
+        for (_, write) in writes {
+            assert_eq!(VectorWrite::from_data(write.encode()), write);
+        }
+
+        // NE does not hold with Z set, CS does with C set
+        assert!(!condition_passed(0x1, 1 << 30));
+        assert!(condition_passed(0x1, 0));
+        assert!(condition_passed(0x2, 1 << 29));
+        assert!(condition_passed(0xe, 0));
+    }
+}
@@ -0,0 +1,245 @@
+/// Building full flash images from fuzzer inputs
+use std::fmt;
+use std::ops::Range;
+
+use libafl_qemu::GuestAddr;
+use log;
+
+use crate::{ChecksumAlgorithm, ChecksumConfig, Directory, InputConfig};
+
+/// Offset of the flash address in the flash image
+pub fn flash_offset(addr: GuestAddr) -> usize {
+    (addr & 0x00FF_FFFF) as usize
+}
+
+/// Fletcher-32 over little-endian 16-bit words, as used by the PSP directories
+pub fn fletcher32(data: &[u8]) -> u32 {
+    let mut c0: u64 = 0xffff;
+    let mut c1: u64 = 0xffff;
+    for block in data.chunks(360 * 2) {
+        for word in block.chunks(2) {
+            c0 += u64::from(u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+            c1 += c0;
+        }
+        c0 = (c0 & 0xffff) + (c0 >> 16);
+        c1 = (c1 & 0xffff) + (c1 >> 16);
+    }
+    c0 = (c0 & 0xffff) + (c0 >> 16);
+    c1 = (c1 & 0xffff) + (c1 >> 16);
+    ((c1 << 16) | c0) as u32
+}
+
+/// Flash contents at `offset` after `writes`, erased beyond the base image
+fn read_flash(base: &[u8], writes: &[(GuestAddr, Vec<u8>)], offset: usize, len: usize) -> Vec<u8> {
+    let mut data = vec![0xff; len];
+    let end = offset + len;
+    if offset < base.len() {
+        let base_end = end.min(base.len());
+        data[..base_end - offset].copy_from_slice(&base[offset..base_end]);
+    }
+    for (addr, write) in writes {
+        let start = flash_offset(*addr);
+        let from = start.max(offset);
+        let to = (start + write.len()).min(end);
+        if from < to {
+            data[from - offset..to - offset].copy_from_slice(&write[from - start..to - start]);
+        }
+    }
+    data
+}
+
+/// Flash range covered by a checksum. A directory in a fuzzed region ends with
+/// the region, one counting more entries than fit is left alone.
+fn checksum_range(
+    base: &[u8],
+    input_conf: &InputConfig,
+    checksum: &ChecksumConfig,
+    writes: &[(GuestAddr, Vec<u8>)],
+) -> Option<Range<usize>> {
+    if let Some(range) = &checksum.data {
+        return Some(flash_offset(range.begin)..flash_offset(range.end));
+    }
+    // The directory magic is right in front of its checksum
+    let offset = flash_offset(checksum.field).checked_sub(4)?;
+    let dir = Directory::parse(&read_flash(base, writes, offset, 12), 0)?;
+    let end = input_conf
+        .mem
+        .iter()
+        .map(|mem| flash_offset(mem.addr)..flash_offset(mem.addr) + mem.size)
+        .find(|region| region.contains(&offset))
+        .map_or(base.len(), |region| region.end);
+    let len = end.saturating_sub(offset);
+    if dir.num_entries as usize > dir.capacity(len) {
+        log::debug!(
+            "Directory at {:#x} counts {:#x} entries, not fixing its checksum",
+            offset,
+            dir.num_entries
+        );
+        return None;
+    }
+    let range = dir.checksum_range(len);
+    Some(offset + range.start..offset + range.end)
+}
+
+/// Recomputes the configured checksums over the flash contents after `writes`
+/// and appends the checksum fields to `writes`, so they are written last
+pub fn fix_checksums(
+    base: &[u8],
+    input_conf: &InputConfig,
+    writes: &mut Vec<(GuestAddr, Vec<u8>)>,
+) {
+    for checksum in &input_conf.checksums {
+        let Some(range) = checksum_range(base, input_conf, checksum, writes) else {
+            log::debug!("No directory for the checksum at {:#010x}", checksum.field);
+            continue;
+        };
+        let data = read_flash(base, writes, range.start, range.len());
+        let value = match checksum.algorithm {
+            ChecksumAlgorithm::Fletcher32 => fletcher32(&data),
+        };
+        writes.push((checksum.field, value.to_le_bytes().to_vec()));
+    }
+}
+
+/// A write to the flash that is not covered by the base image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutsideImage {
+    pub addr: GuestAddr,
+    pub len: usize,
+    pub image_len: usize,
+}
+
+impl fmt::Display for OutsideImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "write of {:#x} bytes at {:#010x} is outside of the {:#x} byte base image",
+            self.len, self.addr, self.image_len
+        )
+    }
+}
+
+impl std::error::Error for OutsideImage {}
+
+/// Applies an input to the base flash image exactly like the harness does,
+/// including the checksum fixups
+pub fn build_flash_image(
+    base: &[u8],
+    input_conf: &InputConfig,
+    input: &[u8],
+) -> Result<Vec<u8>, OutsideImage> {
+    let mut image = base.to_vec();
+    let mut writes = input_conf.flash_writes(input);
+    fix_checksums(base, input_conf, &mut writes);
+    for (addr, data) in writes {
+        let offset = flash_offset(addr);
+        let Some(dst) = image.get_mut(offset..offset + data.len()) else {
+            return Err(OutsideImage {
+                addr,
+                len: data.len(),
+                image_len: base.len(),
+            });
+        };
+        dst.copy_from_slice(&data);
+    }
+    Ok(image)
+}
+
+/// The input a flash image contains in the `mem` regions, the inverse of [`build_flash_image`]
+pub fn input_from_image(image: &[u8], input_conf: &InputConfig) -> Vec<u8> {
+    input_conf
+        .mem
+        .iter()
+        .flat_map(|mem| read_flash(image, &[], flash_offset(mem.addr), mem.size))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_u32;
+
+    #[test]
+    fn fletcher32_reference() {
+        assert_eq!(fletcher32(b"abcdef"), 0x5650_2d2a);
+        assert_eq!(fletcher32(b"abcdefgh"), 0xebe1_9591);
+    }
+
+    #[test]
+    fn fix_psp_directory() {
+        let mut base = vec![0xff; 0x100];
+        base[0x10..0x14].copy_from_slice(b"$PSP");
+        base[0x18..0x1c].copy_from_slice(&2u32.to_le_bytes());
+        let text = "
+initial:
+mem:
+  - addr: 0xff000020
+    size: 0x20
+fixed:
+  - addr: 0x28
+    val: 0x1000
+checksums:
+  - algorithm: Fletcher32
+    field: 0x14
+  - algorithm: Fletcher32
+    field: 0x80
+    data:
+      begin: 0x10
+      end: 0x18
+  - algorithm: Fletcher32
+    field: 0x44
+";
+        let input_conf: InputConfig = serde_yaml::from_str(text).unwrap();
+        let image = build_flash_image(&base, &input_conf, &[0x11; 0x20]).unwrap();
+        assert_eq!(read_u32(&image, 0x28), Some(0x1000));
+        // Covers the entry count and both entries, including the fixed value
+        assert_eq!(read_u32(&image, 0x14), Some(fletcher32(&image[0x18..0x40])));
+        // Sees the checksum written before
+        assert_eq!(read_u32(&image, 0x80), Some(fletcher32(&image[0x10..0x18])));
+        // No directory in front of the field
+        assert_eq!(read_u32(&image, 0x44), Some(0xffff_ffff));
+        // The input as it is in the image, with the fixed value
+        let mut input = vec![0x11; 0x20];
+        input[8..12].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(input_from_image(&image, &input_conf), input);
+
+        // The base image ends in front of the input
+        assert_eq!(
+            build_flash_image(&base[..0x30], &input_conf, &[0x11; 0x20]),
+            Err(OutsideImage {
+                addr: 0xff000020,
+                len: 0x20,
+                image_len: 0x30,
+            })
+        );
+    }
+
+    #[test]
+    fn implausible_entry_count() {
+        let base = vec![0xff; 0x100];
+        let text = "
+initial:
+mem:
+  - addr: 0x10
+    size: 0x30
+fixed:
+checksums:
+  - algorithm: Fletcher32
+    field: 0x14
+";
+        let input_conf: InputConfig = serde_yaml::from_str(text).unwrap();
+        let mut input = vec![0; 0x30];
+        input[..4].copy_from_slice(b"$PSP");
+        input[4..8].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        for (num_entries, fixed) in [(2u32, true), (3, false), (0xffff_ffff, false)] {
+            input[8..12].copy_from_slice(&num_entries.to_le_bytes());
+            let image = build_flash_image(&base, &input_conf, &input).unwrap();
+            let checksum = if fixed {
+                fletcher32(&image[0x18..0x40])
+            } else {
+                0x1234_5678
+            };
+            assert_eq!(read_u32(&image, 0x14), Some(checksum), "{num_entries:#x}");
+        }
+    }
+}
@@ -1,34 +1,84 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
 
 use libafl::prelude::*;
 use libafl_bolts::{impl_serdeany, Named};
 /// Generating metadata whenever a test-case is an objective
-/// Saves all register values
+/// Saves all register values and how the harness exited
 use libafl_qemu::*;
 
 use log;
 use serde::{Deserialize, Serialize};
 
+use crate::{ExceptionType, ResetLevel};
+
+/// Why the harness stopped the emulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitReason {
+    /// One of `harness.sinks` was reached
+    Sink,
+    /// One of `crashes.breakpoints` was reached
+    CrashBreakpoint,
+    /// An entry of the exception vector table was reached
+    Exception,
+    /// A crash detection hook (e.g. `crashes.mmap`) stopped the execution
+    Crash,
+    /// QEMU stopped the execution on its own
+    UnexpectedExit,
+    /// The execution ran longer than `harness.timeout_ms`
+    Timeout,
+    /// The execution ran more than `harness.max_blocks` basic blocks
+    BlockBudget,
+    /// Resetting to the snapshot failed, the test-case did not run
+    ResetFailure,
+}
+
+/// Summary of the last harness execution
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitInfo {
+    pub reason: ExitReason,
+    pub pc: GuestAddr,
+    /// The sink or crash breakpoint that was hit
+    pub hit: Option<GuestAddr>,
+    /// The exception that was triggered
+    pub exception: Option<ExceptionType>,
+    /// Reset level used before the execution
+    pub reset_level: ResetLevel,
+}
+
+static LAST_EXIT: Mutex<Option<ExitInfo>> = Mutex::new(None);
+
+/// Records how the harness exited, read by [`CustomMetadataFeedback`]
+pub fn set_last_exit(info: ExitInfo) {
+    *LAST_EXIT.lock().unwrap() = Some(info);
+}
+
+pub fn last_exit() -> Option<ExitInfo> {
+    LAST_EXIT.lock().unwrap().clone()
+}
+
+/// Whether the last test-case did not run because the reset before it failed
+pub fn reset_failed() -> bool {
+    last_exit().is_some_and(|exit| exit.reason == ExitReason::ResetFailure)
+}
+
+/// Name of a register as used in the metadata files
+pub fn reg_name(reg: Regs) -> String {
+    match reg {
+        Regs::R13 => "sp".to_string(),
+        Regs::R14 => "lr".to_string(),
+        Regs::R15 => "pc".to_string(),
+        Regs::R25 => "cpsr".to_string(),
+        _ => format!("{:?}", reg).to_lowercase(),
+    }
+}
+
 /// A custom testcase metadata
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CustomMetadata {
-    pub r0: String,
-    pub r1: String,
-    pub r2: String,
-    pub r3: String,
-    pub r4: String,
-    pub r5: String,
-    pub r6: String,
-    pub r7: String,
-    pub r8: String,
-    pub r9: String,
-    pub r10: String,
-    pub r11: String,
-    pub r12: String,
-    pub sp: String,
-    pub pc: String,
-    pub lr: String,
-    pub cpsr: String,
+    pub regs: BTreeMap<String, u32>,
+    pub exit: Option<ExitInfo>,
 }
 
 impl_serdeany!(CustomMetadata);
@@ -36,26 +86,8 @@ impl_serdeany!(CustomMetadata);
 impl CustomMetadata {
     /// Creates a new [`struct@CustomMetadata`]
     #[must_use]
-    pub fn new(regs: Vec<u64>) -> Self {
-        Self {
-            r0: format!("{:#010x}", regs[0]),
-            r1: format!("{:#010x}", regs[1]),
-            r2: format!("{:#010x}", regs[2]),
-            r3: format!("{:#010x}", regs[3]),
-            r4: format!("{:#010x}", regs[4]),
-            r5: format!("{:#010x}", regs[5]),
-            r6: format!("{:#010x}", regs[6]),
-            r7: format!("{:#010x}", regs[7]),
-            r8: format!("{:#010x}", regs[8]),
-            r9: format!("{:#010x}", regs[9]),
-            r10: format!("{:#010x}", regs[10]),
-            r11: format!("{:#010x}", regs[11]),
-            r12: format!("{:#010x}", regs[12]),
-            sp: format!("{:#010x}", regs[13]),
-            pc: format!("{:#010x}", regs[14]),
-            lr: format!("{:#010x}", regs[15]),
-            cpsr: format!("{:#010x}", regs[16]),
-        }
+    pub fn new(regs: BTreeMap<String, u32>, exit: Option<ExitInfo>) -> Self {
+        Self { regs, exit }
     }
 }
 
@@ -80,8 +112,10 @@ where
         EM: EventFirer,
         OT: ObserversTuple<S>,
     {
-        log::info!("CustomMetadataFeedback=True");
-        Ok(true)
+        // A test-case that did not run has no metadata to report
+        let interesting = !reset_failed();
+        log::info!("CustomMetadataFeedback={}", interesting);
+        Ok(interesting)
     }
 
     fn append_metadata<EM, OT>(
@@ -92,12 +126,13 @@ where
         testcase: &mut Testcase<S::Input>,
     ) -> Result<(), Error> {
         // Read regs
-        let mut regs = Vec::new();
+        let mut regs = BTreeMap::new();
         log::info!("Number of cpus is: {}", self.emulator.num_cpus());
         for r in Regs::iter() {
-            regs.push(self.emulator.cpu_from_index(0).read_reg(r).unwrap());
+            let value: u32 = self.emulator.cpu_from_index(0).read_reg(r).unwrap();
+            regs.insert(reg_name(r), value);
         }
-        testcase.add_metadata(CustomMetadata::new(regs));
+        testcase.add_metadata(CustomMetadata::new(regs, last_exit()));
         Ok(())
     }
 }
@@ -116,3 +151,42 @@ impl CustomMetadataFeedback {
         Self { emulator }
     }
 }
+
+/// Interesting if the reset before the test-case failed and it did not run
+#[derive(Clone, Debug, Default)]
+pub struct ResetFailureFeedback {}
+
+impl<S> Feedback<S> for ResetFailureFeedback
+where
+    S: UsesInput + State,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer,
+        OT: ObserversTuple<S>,
+    {
+        Ok(reset_failed())
+    }
+}
+
+impl Named for ResetFailureFeedback {
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("ResetFailureFeedback")
+    }
+}
+
+impl ResetFailureFeedback {
+    /// Creates a new [`ResetFailureFeedback`]
+    #[must_use]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,188 @@
+/// Detecting hangs: the basic block budget, the replay deadline and the hang objective
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libafl::prelude::*;
+use libafl_bolts::{AsSlice, Named};
+use libafl_qemu::GuestAddr;
+use log;
+
+use crate::crash_bucket::fnv1a;
+use crate::last_exit;
+
+/// Basic blocks executed since the start of the current execution
+static EXECUTED_BLOCKS: AtomicU64 = AtomicU64::new(0);
+static BUDGET_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+/// Wall-clock limit of an execution checked in the block hooks, set for replays.
+/// While fuzzing the executor's timeout stops the execution.
+static WALL_CLOCK_LIMIT: Mutex<Option<Duration>> = Mutex::new(None);
+static DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+static DEADLINE_PASSED: AtomicBool = AtomicBool::new(false);
+
+/// Resets the block budget and the deadline, called before every execution
+pub fn start_block_budget() {
+    EXECUTED_BLOCKS.store(0, Ordering::Relaxed);
+    BUDGET_EXHAUSTED.store(false, Ordering::Relaxed);
+    DEADLINE_PASSED.store(false, Ordering::Relaxed);
+    *DEADLINE.lock().unwrap() = WALL_CLOCK_LIMIT
+        .lock()
+        .unwrap()
+        .map(|limit| Instant::now() + limit);
+}
+
+pub fn limit_wall_clock(limit: Duration) {
+    *WALL_CLOCK_LIMIT.lock().unwrap() = Some(limit);
+}
+
+/// Returns true once the deadline of the current execution passed,
+/// the caller then stops the execution
+pub fn check_deadline() -> bool {
+    let passed = DEADLINE
+        .lock()
+        .unwrap()
+        .is_some_and(|deadline| Instant::now() >= deadline);
+    if passed {
+        DEADLINE_PASSED.store(true, Ordering::Relaxed);
+    }
+    passed
+}
+
+pub fn deadline_passed() -> bool {
+    DEADLINE_PASSED.load(Ordering::Relaxed)
+}
+
+/// Counts an executed block. Returns true for every block beyond `max_blocks`,
+/// the caller then stops the execution.
+pub fn count_block(max_blocks: u64) -> bool {
+    let executed = EXECUTED_BLOCKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if executed > max_blocks {
+        BUDGET_EXHAUSTED.store(true, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+pub fn block_budget_exhausted() -> bool {
+    BUDGET_EXHAUSTED.load(Ordering::Relaxed)
+}
+
+pub fn executed_blocks() -> u64 {
+    EXECUTED_BLOCKS.load(Ordering::Relaxed)
+}
+
+/// Keeps timed out executions as objectives, one per hang site, stored in their own directory
+#[derive(Debug)]
+pub struct HangFeedback {
+    dir: PathBuf,
+    seen: BTreeSet<GuestAddr>,
+    last: bool,
+}
+
+impl HangFeedback {
+    /// Creates a new [`HangFeedback`] storing its solutions in `dir`
+    #[must_use]
+    pub fn new(dir: PathBuf) -> Self {
+        fs::create_dir_all(&dir).unwrap();
+        Self {
+            dir,
+            seen: BTreeSet::new(),
+            last: false,
+        }
+    }
+}
+
+impl<S> Feedback<S> for HangFeedback
+where
+    S: UsesInput + State,
+    S::Input: HasTargetBytes,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer,
+        OT: ObserversTuple<S>,
+    {
+        if *exit_kind != ExitKind::Timeout {
+            return Ok(false);
+        }
+        let pc = last_exit().map_or(0, |exit| exit.pc);
+        self.last = self.seen.insert(pc);
+        log::info!(
+            "Hang at {:#x} after {} blocks{}",
+            pc,
+            executed_blocks(),
+            if self.last { "" } else { " (known)" }
+        );
+        Ok(self.last)
+    }
+
+    fn append_metadata<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _em: &mut EM,
+        _ot: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error> {
+        if !std::mem::take(&mut self.last) {
+            return Ok(());
+        }
+        // The solutions corpus writes the input to a preset file path
+        if let Some(input) = testcase.input() {
+            let name = format!("hang_{:016x}", fnv1a(input.target_bytes().as_slice()));
+            *testcase.file_path_mut() = Some(self.dir.join(&name));
+            *testcase.filename_mut() = Some(name);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.last = false;
+        Ok(())
+    }
+}
+
+impl Named for HangFeedback {
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("HangFeedback")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_budget() {
+        start_block_budget();
+        assert!(!count_block(2));
+        assert!(!count_block(2));
+        assert!(!block_budget_exhausted());
+        assert!(count_block(2));
+        assert!(block_budget_exhausted());
+        assert_eq!(executed_blocks(), 3);
+        start_block_budget();
+        assert!(!block_budget_exhausted());
+        assert_eq!(executed_blocks(), 0);
+
+        // Without a wall-clock limit there is no deadline
+        assert!(!check_deadline());
+        limit_wall_clock(Duration::ZERO);
+        start_block_budget();
+        assert!(!deadline_passed());
+        assert!(check_deadline());
+        assert!(deadline_passed());
+    }
+}
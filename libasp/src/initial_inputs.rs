@@ -3,7 +3,7 @@ use libafl_qemu::GuestAddr;
 use std::fs;
 use std::path::PathBuf;
 
-use crate::MemConfig;
+use crate::{MailboxConfig, MboxCommand, MemConfig};
 
 pub struct InitialInput {}
 
@@ -53,4 +53,18 @@ impl InitialInput {
         }
         input_dir
     }
+
+    /// Writes the command sequences of `mailbox.initial`, or a single empty command
+    pub fn create_mailbox_inputs(&self, mailbox: &MailboxConfig, input_dir: PathBuf) -> PathBuf {
+        let mut inputs = mailbox.initial_inputs();
+        if inputs.is_empty() {
+            inputs.push(MboxCommand::default().to_bytes());
+        }
+        for (i, input) in inputs.iter().enumerate() {
+            let mut new_input_path = PathBuf::from(&input_dir);
+            new_input_path.push(format!("input{:#04}", i));
+            fs::write(new_input_path, input).unwrap();
+        }
+        input_dir
+    }
 }
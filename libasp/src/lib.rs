@@ -1,26 +1,74 @@
 // Linux only
 #![cfg(target_os = "linux")]
 
+// Grouping objectives by their crash site
+pub mod crash_bucket;
+pub use crash_bucket::*;
+
+// Saving and restoring the emulated PSP devices
+pub mod devices;
+pub use devices::*;
+
+// Tracking the SRAM pages written by a test-case
+pub mod dirty_pages;
+pub use dirty_pages::*;
+
+// Keeping the DrCov trace of a resumed campaign
+pub mod drcov;
+pub use drcov::*;
+
 // Catching CPU exception during the execution
 pub mod exception_handler;
 pub use exception_handler::*;
 
+// Building full flash images from inputs
+pub mod flash_image;
+pub use flash_image::*;
+
 // Generate metadata for each objective
 pub mod gen_metadata;
 pub use gen_metadata::*;
 
+// Stopping and keeping executions that hang
+pub mod hang;
+pub use hang::*;
+
 // Generate initial inputs from provided UEFI images
 pub mod initial_inputs;
 pub use initial_inputs::*;
 
+// x86-to-PSP mailbox commands as fuzzing input
+pub mod mailbox;
+pub use mailbox::*;
+
+// Parsing the PSP and BIOS directories in flash
+pub mod psp_directory;
+pub use psp_directory::*;
+
+// Mutating the PSP directories of an input
+pub mod psp_mutations;
+pub use psp_mutations::*;
+
 // Resetting the state aka. snapshotting in between fuzzing test-cases
 pub mod reset_state;
 pub use reset_state::*;
 
+// Choosing the corpus scheduler from the config
+pub mod scheduler;
+pub use scheduler::*;
+
+// Resolving `symbol+offset` addresses from a symbol file
+pub mod symbols;
+pub use symbols::*;
+
 // Tunneling comparisons by statically/dynamically setting register values
 pub mod tunneling;
 pub use tunneling::*;
 
+// Checking the YAML config before starting the emulator
+pub mod validate;
+pub use validate::*;
+
 // Parsing the YAML config
 pub mod yaml_conf;
 pub use yaml_conf::*;
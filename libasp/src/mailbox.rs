@@ -0,0 +1,300 @@
+/// x86-to-PSP mailbox commands: the typed input of the mailbox harness mode and
+/// their delivery through the C2P mailbox registers in the PSP address space
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libafl_qemu::*;
+use log;
+
+use crate::MailboxConfig;
+
+/// Offsets of the C2P mailbox registers at `mailbox.regs`:
+/// the `MboxStruct` followed by the 64-bit buffer pointer
+const MBOX_CMD_REG: GuestAddr = 0;
+const MBOX_BUFFER_LO_REG: GuestAddr = 4;
+const MBOX_BUFFER_HI_REG: GuestAddr = 8;
+
+/// Set while the workloop runs on from `mailbox.idle` without the breakpoint there
+static LEAVING_IDLE: AtomicBool = AtomicBool::new(false);
+/// Set once the workloop left `mailbox.idle`, the breakpoint is then set again
+static LEFT_IDLE: AtomicBool = AtomicBool::new(false);
+
+/// `CmdOrRspns` of the `MboxStruct`, set by the PSP once it handled the command
+const MBOX_RESPONSE: u32 = 1 << 31;
+
+/// Bytes of a command in the input before its payload:
+/// ID, status bits, data, buffer pointer and payload length
+pub const MBOX_HEADER_SIZE: usize = 14;
+
+/// A command written by the x86 side, see `script/parse_mbox.py` for the `MboxStruct`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MboxCommand {
+    pub id: u8,
+    /// `AltStat`, `ResetRequired` (bit 4) and `Recovery` (bit 5)
+    pub status: u8,
+    /// `StatOrDta`
+    pub data: u16,
+    pub buffer_ptr: u64,
+    pub payload: Vec<u8>,
+}
+
+impl MboxCommand {
+    /// Reads the next command from `input`, returns it and the rest of the input
+    fn parse(input: &[u8], max_payload: usize) -> Option<(Self, &[u8])> {
+        if input.len() < MBOX_HEADER_SIZE {
+            return None;
+        }
+        let (header, rest) = input.split_at(MBOX_HEADER_SIZE);
+        let len = usize::from(u16::from_le_bytes([header[12], header[13]]))
+            .min(max_payload)
+            .min(rest.len());
+        let (payload, rest) = rest.split_at(len);
+        let command = Self {
+            id: header[0],
+            status: header[1] & 0x3f,
+            data: u16::from_le_bytes([header[2], header[3]]),
+            buffer_ptr: u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            payload: payload.to_vec(),
+        };
+        Some((command, rest))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.id, self.status];
+        bytes.extend_from_slice(&self.data.to_le_bytes());
+        bytes.extend_from_slice(&self.buffer_ptr.to_le_bytes());
+        bytes.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Value of the command register, `CmdOrRspns` and the reserved bit are zero
+    pub fn register(&self) -> u32 {
+        let recovery = u32::from((self.status >> 5) & 1) << 30;
+        let reset_required = u32::from((self.status >> 4) & 1) << 29;
+        let alt_status = u32::from(self.status & 0xf) << 24;
+        recovery | reset_required | alt_status | (u32::from(self.id) << 16) | u32::from(self.data)
+    }
+
+    pub fn name(&self) -> &'static str {
+        command_name(self.id).unwrap_or("Unknown")
+    }
+}
+
+impl fmt::Display for MboxCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#04x} {}: {:#010x}, buffer {:#x}, payload [",
+            self.id,
+            self.name(),
+            self.register(),
+            self.buffer_ptr
+        )?;
+        for byte in &self.payload {
+            write!(f, "{byte:02x}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// The commands of an input, at most `max_commands` with `max_payload` bytes each
+pub fn parse_commands(input: &[u8], conf: &MailboxConfig) -> Vec<MboxCommand> {
+    let mut commands = Vec::new();
+    let mut input = input;
+    while commands.len() < conf.max_commands {
+        let Some((command, rest)) = MboxCommand::parse(input, conf.max_payload) else {
+            break;
+        };
+        commands.push(command);
+        input = rest;
+    }
+    commands
+}
+
+impl MailboxConfig {
+    /// Largest input that still has an effect
+    pub fn max_input_size(&self) -> usize {
+        self.max_commands * (MBOX_HEADER_SIZE + self.max_payload)
+    }
+
+    /// The initial inputs, their commands point to `buffer_ptr` and have no payload
+    pub fn initial_inputs(&self) -> Vec<Vec<u8>> {
+        self.initial
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .flat_map(|&id| {
+                        MboxCommand {
+                            id,
+                            buffer_ptr: self.buffer_ptr,
+                            ..MboxCommand::default()
+                        }
+                        .to_bytes()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Writes the payload to the command buffer and the command to the C2P mailbox registers
+pub fn send_command(emu: &Qemu, conf: &MailboxConfig, command: &MboxCommand) {
+    log::debug!("Mailbox command {}", command);
+    let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+    let write_reg = |offset: GuestAddr, value: u32| unsafe {
+        cpu.write_mem(conf.regs + offset, &value.to_le_bytes());
+    };
+    if let Some(buffer) = conf.buffer {
+        unsafe {
+            cpu.write_mem(buffer, &command.payload);
+        }
+    }
+    write_reg(MBOX_BUFFER_LO_REG, command.buffer_ptr as u32);
+    write_reg(MBOX_BUFFER_HI_REG, (command.buffer_ptr >> 32) as u32);
+    // Last, the workloop takes the command once the command register is written
+    write_reg(MBOX_CMD_REG, command.register());
+}
+
+/// The command register once the PSP set the response bit
+pub fn mailbox_response(emu: &Qemu, conf: &MailboxConfig) -> Option<u32> {
+    let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+    let mut reg = [0; 4];
+    unsafe {
+        cpu.read_mem(conf.regs + MBOX_CMD_REG, &mut reg);
+    }
+    let reg = u32::from_le_bytes(reg);
+    (reg & MBOX_RESPONSE != 0).then_some(reg)
+}
+
+/// Sends the next command while the emulator stopped at `mailbox.idle`.
+/// The breakpoint there is removed until the workloop left it, otherwise the
+/// emulator would stop again right away, see [`check_left_idle`].
+pub fn send_from_idle(emu: &Qemu, conf: &MailboxConfig, command: &MboxCommand) {
+    emu.remove_breakpoint(conf.idle);
+    LEAVING_IDLE.store(true, Ordering::Relaxed);
+    send_command(emu, conf, command);
+}
+
+/// Called for every executed block in the mailbox mode. Returns true for the first
+/// block outside of `mailbox.idle` after [`send_from_idle`], the caller then stops the
+/// execution so that [`take_left_idle`] sets the breakpoint again.
+pub fn check_left_idle(emu: &Qemu, conf: &MailboxConfig) -> bool {
+    if !LEAVING_IDLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let pc: GuestAddr = emu.current_cpu().unwrap().read_reg(Regs::Pc).unwrap();
+    if pc == conf.idle {
+        return false;
+    }
+    LEAVING_IDLE.store(false, Ordering::Relaxed);
+    LEFT_IDLE.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Sets the breakpoint at `mailbox.idle` again once the workloop left it,
+/// returns whether the emulator stopped for that
+pub fn take_left_idle(emu: &Qemu, conf: &MailboxConfig) -> bool {
+    let left = LEFT_IDLE.swap(false, Ordering::Relaxed);
+    if left {
+        emu.set_breakpoint(conf.idle);
+    }
+    left
+}
+
+/// Sets the breakpoint at `mailbox.idle` again if the last execution ended before
+/// the workloop left it, called before every execution
+pub fn reset_idle(emu: &Qemu, conf: &MailboxConfig) {
+    if LEAVING_IDLE.swap(false, Ordering::Relaxed) | LEFT_IDLE.swap(false, Ordering::Relaxed) {
+        emu.set_breakpoint(conf.idle);
+    }
+}
+
+/// Name of a BIOS-to-PSP command
+pub fn command_name(id: u8) -> Option<&'static str> {
+    let name = match id {
+        0x02 => "MboxBiosCmdSmmInfo",
+        0x03 => "MboxBiosCmdSxInfo",
+        0x04 => "MboxBiosCmdRsmInfo",
+        0x05 => "MboxBiosCmdPspQuery",
+        0x06 => "MboxBiosCmdBootDone",
+        0x14 => "MboxBiosCmdHSTIQuery",
+        0x19 => "MboxBiosCmdGetVersion",
+        0x1a => "MboxBiosCmdSetFuse",
+        0x1b => "MboxBiosCmdLockDFReg",
+        0x1c => "MboxBiosCmdClrSmmLock",
+        0x1d => "MboxBiosCmdSetApCsBase",
+        0x1e => "MboxBiosCmdKvmInfo",
+        0x1f => "MboxBiosCmdLockSpi",
+        0x20 => "MboxBiosCmdScreenOnGpio",
+        0x21 => "MboxBiosCmdSpiOpWhiteList",
+        0x22 => "MboxBiosCmdPsbAutoFusing",
+        0x24 => "MboxBiosCmdRasEinj",
+        0x25 => "MboxBiosCmdStopArs",
+        0x26 => "MboxBiosCmdSetBootPartitionId",
+        0x27 => "MboxBiosCmdPspCapsQuery",
+        0x2d => "MboxBiosCmdLaterSplFuse",
+        0x2e => "MboxBiosCmdDtpmInfo",
+        0x2f => "BIOS_CMD_VALIDATE_MAN_OS_SIGNATURE",
+        0x30 => "MboxBiosCmdLockFCHReg",
+        0x31 => "BIOS_CMD_GET_DRTM_INFO",
+        0x39 => "MboxBiosCmdSetRpmcAddress",
+        0x3a => "MboxBiosCmdLockGPIO",
+        0x3f => "MboxBiosCmdSendIvrsAcpiTable",
+        0x40 => "MboxBiosCmdTa",
+        0x41 => "BIOS_CMD_ACPI_RAS_EINJ",
+        0x42 => "MboxBiosCmdQueryTCGLog",
+        0x47 => "MboxBiosCmdQuerySplFuse",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_sequence() {
+        let conf = MailboxConfig {
+            regs: 0x0301_0570,
+            idle: 0x210,
+            buffer: None,
+            buffer_ptr: 0x7ff0_0000,
+            max_commands: 2,
+            max_payload: 4,
+            initial: vec![vec![0x02, 0x03]],
+        };
+        let smm_info = MboxCommand {
+            id: 0x02,
+            status: 0x31,
+            data: 0x1234,
+            buffer_ptr: 0x1_7ff0_0000,
+            payload: vec![1, 2, 3, 4, 5, 6],
+        };
+        assert_eq!(smm_info.register(), 0x6102_1234);
+        assert_eq!(smm_info.name(), "MboxBiosCmdSmmInfo");
+        assert_eq!(
+            smm_info.to_string(),
+            "0x02 MboxBiosCmdSmmInfo: 0x61021234, buffer 0x17ff00000, payload [010203040506]"
+        );
+
+        // The payload is cut to `max_payload`, the rest is the next command
+        let mut input = smm_info.to_bytes();
+        input.extend_from_slice(&[0; MBOX_HEADER_SIZE + 1]);
+        let commands = parse_commands(&input, &conf);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].payload, vec![1, 2, 3, 4]);
+        assert_eq!(commands[0].buffer_ptr, 0x1_7ff0_0000);
+        assert_eq!(commands[1].id, 0x05);
+
+        let initial = conf.initial_inputs();
+        let commands = parse_commands(&initial[0], &conf);
+        assert_eq!(
+            commands.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![0x02, 0x03]
+        );
+        assert!(commands.iter().all(|c| c.buffer_ptr == 0x7ff0_0000));
+        assert_eq!(conf.max_input_size(), 2 * (MBOX_HEADER_SIZE + 4));
+    }
+}
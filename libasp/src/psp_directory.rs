@@ -0,0 +1,303 @@
+/// Layout of the AMD PSP and BIOS directories in flash
+use std::ops::Range;
+
+pub fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, val: u32) -> bool {
+    match buf.get_mut(offset..offset + 4) {
+        Some(dst) => {
+            dst.copy_from_slice(&val.to_le_bytes());
+            true
+        }
+        None => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectoryKind {
+    Psp,
+    PspL2,
+    Bhd,
+    BhdL2,
+    ComboPsp,
+    ComboBhd,
+}
+
+const DIRECTORY_KINDS: [DirectoryKind; 6] = [
+    DirectoryKind::Psp,
+    DirectoryKind::PspL2,
+    DirectoryKind::Bhd,
+    DirectoryKind::BhdL2,
+    DirectoryKind::ComboPsp,
+    DirectoryKind::ComboBhd,
+];
+
+/// Fields of a directory entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryField {
+    /// Entry type, or the id selector for combo entries
+    Type,
+    Size,
+    /// Flash address of the entry's data or of a sub directory
+    Location,
+    /// RAM address the BIOS entry is copied to
+    Destination,
+}
+
+impl DirectoryKind {
+    pub fn magic(self) -> &'static [u8; 4] {
+        match self {
+            DirectoryKind::Psp => b"$PSP",
+            DirectoryKind::PspL2 => b"$PL2",
+            DirectoryKind::Bhd => b"$BHD",
+            DirectoryKind::BhdL2 => b"$BL2",
+            DirectoryKind::ComboPsp => b"2PSP",
+            DirectoryKind::ComboBhd => b"2BHD",
+        }
+    }
+
+    pub fn from_magic(magic: &[u8]) -> Option<Self> {
+        DIRECTORY_KINDS
+            .into_iter()
+            .find(|kind| kind.magic()[..] == *magic)
+    }
+
+    /// Size of the header after the checksum field, including the entry count
+    pub fn header_len(self) -> usize {
+        match self {
+            DirectoryKind::ComboPsp | DirectoryKind::ComboBhd => 0x18,
+            _ => 0x8,
+        }
+    }
+
+    pub fn entry_size(self) -> usize {
+        match self {
+            DirectoryKind::Bhd | DirectoryKind::BhdL2 => 0x18,
+            _ => 0x10,
+        }
+    }
+
+    /// Offsets of the fields inside an entry
+    pub fn entry_fields(self) -> &'static [(EntryField, usize)] {
+        match self {
+            DirectoryKind::Psp | DirectoryKind::PspL2 => &[
+                (EntryField::Type, 0x0),
+                (EntryField::Size, 0x4),
+                (EntryField::Location, 0x8),
+            ],
+            DirectoryKind::Bhd | DirectoryKind::BhdL2 => &[
+                (EntryField::Type, 0x0),
+                (EntryField::Size, 0x4),
+                (EntryField::Location, 0x8),
+                (EntryField::Destination, 0x10),
+            ],
+            DirectoryKind::ComboPsp | DirectoryKind::ComboBhd => {
+                &[(EntryField::Type, 0x0), (EntryField::Location, 0x8)]
+            }
+        }
+    }
+}
+
+/// A directory header found in a buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Directory {
+    pub kind: DirectoryKind,
+    /// Offset of the magic in the buffer
+    pub offset: usize,
+    pub num_entries: u32,
+}
+
+impl Directory {
+    /// Parses the directory header at `offset`, if there is one
+    pub fn parse(buf: &[u8], offset: usize) -> Option<Self> {
+        let kind = DirectoryKind::from_magic(buf.get(offset..offset + 4)?)?;
+        let num_entries = read_u32(buf, offset + 8)?;
+        Some(Self {
+            kind,
+            offset,
+            num_entries,
+        })
+    }
+
+    pub fn num_entries_offset(&self) -> usize {
+        self.offset + 8
+    }
+
+    pub fn entry_offset(&self, idx: usize) -> usize {
+        self.offset + 8 + self.kind.header_len() + idx * self.kind.entry_size()
+    }
+
+    /// Number of entries fitting in front of `end`
+    pub fn capacity(&self, end: usize) -> usize {
+        end.saturating_sub(self.entry_offset(0)) / self.kind.entry_size()
+    }
+
+    /// Number of entries that are both counted and in front of `end`
+    pub fn entries(&self, end: usize) -> usize {
+        (self.num_entries as usize).min(self.capacity(end))
+    }
+
+    /// Bytes covered by the checksum, clamped to `len`
+    pub fn checksum_range(&self, len: usize) -> Range<usize> {
+        let start = self.num_entries_offset();
+        let end = (self.num_entries as usize)
+            .saturating_mul(self.kind.entry_size())
+            .saturating_add(start + self.kind.header_len())
+            .min(len);
+        start..end.max(start)
+    }
+
+    pub fn set_num_entries(&mut self, buf: &mut [u8], num_entries: u32) {
+        if write_u32(buf, self.num_entries_offset(), num_entries) {
+            self.num_entries = num_entries;
+        }
+    }
+
+    /// Offset of an entry field, if the entry has it
+    pub fn field_offset(&self, idx: usize, field: EntryField) -> Option<usize> {
+        self.kind
+            .entry_fields()
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, offset)| self.entry_offset(idx) + offset)
+    }
+
+    /// Inserts `entry` at `idx`, moving the following entries back.
+    /// Fails if no entry slot is left in front of `end`.
+    pub fn insert_entry(
+        &mut self,
+        buf: &mut [u8],
+        end: usize,
+        idx: usize,
+        entry: &[u8],
+        fix_count: bool,
+    ) -> bool {
+        let entries = self.entries(end);
+        let size = self.kind.entry_size();
+        if idx > entries || entries >= self.capacity(end) || entry.len() != size {
+            return false;
+        }
+        buf.copy_within(
+            self.entry_offset(idx)..self.entry_offset(entries),
+            self.entry_offset(idx + 1),
+        );
+        buf[self.entry_offset(idx)..self.entry_offset(idx + 1)].copy_from_slice(entry);
+        if fix_count {
+            self.set_num_entries(buf, self.num_entries.saturating_add(1));
+        }
+        true
+    }
+
+    /// Removes the entry at `idx`, moving the following entries forward.
+    /// The freed slot reads as erased flash.
+    pub fn remove_entry(
+        &mut self,
+        buf: &mut [u8],
+        end: usize,
+        idx: usize,
+        fix_count: bool,
+    ) -> bool {
+        let entries = self.entries(end);
+        if idx >= entries {
+            return false;
+        }
+        buf.copy_within(
+            self.entry_offset(idx + 1)..self.entry_offset(entries),
+            self.entry_offset(idx),
+        );
+        buf[self.entry_offset(entries - 1)..self.entry_offset(entries)].fill(0xff);
+        if fix_count {
+            self.set_num_entries(buf, self.num_entries - 1);
+        }
+        true
+    }
+
+    pub fn swap_entries(&self, buf: &mut [u8], end: usize, a: usize, b: usize) -> bool {
+        let entries = self.entries(end);
+        if a >= entries || b >= entries || a == b {
+            return false;
+        }
+        let (a, b) = (a.min(b), a.max(b));
+        let size = self.kind.entry_size();
+        let (head, tail) = buf.split_at_mut(self.entry_offset(b));
+        head[self.entry_offset(a)..self.entry_offset(a) + size].swap_with_slice(&mut tail[..size]);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A $PSP directory with entries of type 1, 2 and 3 in a 0x60 byte region
+    fn psp_directory() -> Vec<u8> {
+        let mut buf = vec![0xff; 0x60];
+        buf[0x0..0x4].copy_from_slice(b"$PSP");
+        write_u32(&mut buf, 0x8, 3);
+        for (i, offset) in [0x10, 0x20, 0x30].into_iter().enumerate() {
+            write_u32(&mut buf, offset, i as u32 + 1);
+            write_u32(&mut buf, offset + 4, 0x100);
+            write_u32(&mut buf, offset + 8, 0x1000 * (i as u32 + 1));
+            write_u32(&mut buf, offset + 12, 0);
+        }
+        buf
+    }
+
+    fn entry_types(buf: &[u8], dir: &Directory) -> Vec<u32> {
+        (0..dir.entries(buf.len()))
+            .map(|i| read_u32(buf, dir.entry_offset(i)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_directories() {
+        let buf = psp_directory();
+        let dir = Directory::parse(&buf, 0).unwrap();
+        assert_eq!(dir.kind, DirectoryKind::Psp);
+        assert_eq!(dir.num_entries, 3);
+        assert_eq!(dir.capacity(buf.len()), 5);
+        assert_eq!(dir.field_offset(1, EntryField::Location), Some(0x28));
+        assert_eq!(dir.field_offset(1, EntryField::Destination), None);
+        assert!(Directory::parse(&buf, 0x10).is_none());
+
+        let mut combo = vec![0; 0x40];
+        combo[0..4].copy_from_slice(b"2PSP");
+        let dir = Directory::parse(&combo, 0).unwrap();
+        assert_eq!(dir.entry_offset(0), 0x20);
+    }
+
+    #[test]
+    fn insert_and_remove_keep_count() {
+        let mut buf = psp_directory();
+        let end = buf.len();
+        let mut dir = Directory::parse(&buf, 0).unwrap();
+        let entry = buf[0x30..0x40].to_vec();
+        assert!(dir.insert_entry(&mut buf, end, 0, &entry, true));
+        assert_eq!(entry_types(&buf, &dir), vec![3, 1, 2, 3]);
+        assert_eq!(Directory::parse(&buf, 0).unwrap().num_entries, 4);
+
+        assert!(dir.remove_entry(&mut buf, end, 1, true));
+        assert_eq!(entry_types(&buf, &dir), vec![3, 2, 3]);
+        assert_eq!(read_u32(&buf, dir.entry_offset(3)), Some(0xffff_ffff));
+
+        // Without fixing the count the last entry drops out of the directory
+        assert!(dir.insert_entry(&mut buf, end, 3, &entry, false));
+        assert_eq!(entry_types(&buf, &dir), vec![3, 2, 3]);
+        assert!(dir.swap_entries(&mut buf, end, 0, 1));
+        assert_eq!(entry_types(&buf, &dir), vec![2, 3, 3]);
+    }
+
+    #[test]
+    fn insert_into_full_directory() {
+        let mut buf = psp_directory();
+        let mut dir = Directory::parse(&buf, 0).unwrap();
+        let entry = buf[0x10..0x20].to_vec();
+        assert!(!dir.insert_entry(&mut buf, 0x40, 0, &entry, true));
+        assert!(dir.insert_entry(&mut buf, 0x50, 0, &entry, true));
+        assert_eq!(dir.num_entries, 4);
+    }
+}
@@ -0,0 +1,596 @@
+/// Structure-aware mutations of the PSP directories in the fuzzed flash regions.
+/// The mutations work on a typed `PspInput` converted from and back to the flash
+/// bytes. The corpus keeps `BytesInput`s, so the bytes mutations, tokens and
+/// Grimoire apply as well and solutions can still be flashed as they are.
+use std::borrow::Cow;
+
+use libafl::prelude::*;
+use libafl_bolts::{
+    rands::Rand,
+    tuples::{tuple_list, tuple_list_type},
+    AsSlice, Named,
+};
+use libafl_qemu::GuestAddr;
+
+use crate::{flash_offset, read_u32, write_u32, Directory, EntryField, InputConfig};
+
+/// Values breaking size and bounds checks
+const INTERESTING_U32: [u32; 8] = [
+    0x0,
+    0x1,
+    0xff,
+    0xffff,
+    0x7fff_ffff,
+    0x8000_0000,
+    0xffff_fff0,
+    0xffff_ffff,
+];
+
+/// A fuzzed `input.mem` region
+#[derive(Clone, Debug)]
+pub struct InputRegion {
+    /// Offset in the input
+    pub offset: usize,
+    /// Flash address the region is written to
+    pub addr: GuestAddr,
+    pub size: usize,
+}
+
+/// Where the fuzzed regions and their directories are
+#[derive(Clone, Debug)]
+pub struct PspLayout {
+    pub regions: Vec<InputRegion>,
+    pub flash_size: GuestAddr,
+}
+
+impl PspLayout {
+    pub fn new(input: &InputConfig, flash_size: GuestAddr) -> Self {
+        let mut offset = 0;
+        let mut regions = Vec::new();
+        for mem in input.mem.iter() {
+            regions.push(InputRegion {
+                offset,
+                addr: mem.addr,
+                size: mem.size,
+            });
+            offset += mem.size;
+        }
+        Self {
+            regions,
+            flash_size,
+        }
+    }
+
+    /// Directories at the start of the regions together with the end of their region
+    pub fn directories(&self, input: &[u8]) -> Vec<(Directory, usize)> {
+        self.regions
+            .iter()
+            .filter_map(|region| {
+                let end = (region.offset + region.size).min(input.len());
+                Directory::parse(&input[..end], region.offset).map(|dir| (dir, end))
+            })
+            .collect()
+    }
+
+    /// Flash offset an input offset is written to
+    pub fn flash_offset(&self, offset: usize) -> Option<GuestAddr> {
+        self.regions
+            .iter()
+            .find(|region| offset >= region.offset && offset < region.offset + region.size)
+            .map(|region| {
+                flash_offset(region.addr) as GuestAddr + (offset - region.offset) as GuestAddr
+            })
+    }
+}
+
+fn below<S: HasRand>(state: &mut S, upper: usize) -> usize {
+    (state.rand_mut().next() % upper as u64) as usize
+}
+
+/// Flash bytes of the fuzzed regions together with their PSP directories
+#[derive(Clone, Debug)]
+pub struct PspInput {
+    pub bytes: Vec<u8>,
+    /// Directories at the start of the regions together with the end of their region
+    pub directories: Vec<(Directory, usize)>,
+}
+
+impl PspInput {
+    /// Parses the directories of the fuzzed regions
+    pub fn from_flash_bytes(layout: &PspLayout, bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            directories: layout.directories(bytes),
+        }
+    }
+
+    /// The bytes written to the fuzzed regions, including the mutated directories
+    pub fn to_flash_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// Picks a random directory with at least `min_entries` entries
+    fn choose_directory<S: HasRand>(
+        &self,
+        state: &mut S,
+        min_entries: usize,
+    ) -> Option<(Directory, usize)> {
+        let dirs: Vec<(Directory, usize)> = self
+            .directories
+            .iter()
+            .copied()
+            .filter(|(dir, end)| dir.entries(*end) >= min_entries)
+            .collect();
+        if dirs.is_empty() {
+            return None;
+        }
+        Some(dirs[below(state, dirs.len())])
+    }
+
+    /// Locations of all entries, to point other entries at them
+    fn locations(&self) -> Vec<u32> {
+        let mut locations = Vec::new();
+        for (dir, end) in &self.directories {
+            for idx in 0..dir.entries(*end) {
+                if let Some(location) = dir
+                    .field_offset(idx, EntryField::Location)
+                    .and_then(|offset| read_u32(&self.bytes, offset))
+                {
+                    locations.push(location);
+                }
+            }
+        }
+        locations
+    }
+}
+
+/// Runs a mutation on the `PspInput` of the input bytes
+fn mutate_psp<F>(layout: &PspLayout, input: &mut BytesInput, f: F) -> MutationResult
+where
+    F: FnOnce(&mut PspInput) -> MutationResult,
+{
+    let mut psp = PspInput::from_flash_bytes(layout, input.target_bytes().as_slice());
+    let result = f(&mut psp);
+    if result == MutationResult::Mutated {
+        *input = BytesInput::new(psp.to_flash_bytes());
+    }
+    result
+}
+
+/// Replaces a single type, size, location or destination field of an entry
+#[derive(Debug)]
+pub struct PspEntryFieldMutator {
+    layout: PspLayout,
+}
+
+impl PspEntryFieldMutator {
+    #[must_use]
+    pub fn new(layout: PspLayout) -> Self {
+        Self { layout }
+    }
+
+    fn location<S: HasRand>(
+        &self,
+        state: &mut S,
+        psp: &PspInput,
+        dir: &Directory,
+        old: u32,
+    ) -> u32 {
+        // Keep the mapping of the flash into the address space, e.g. 0xff000000
+        let mapped = |offset: u32| (old & 0xff00_0000) | (offset & 0x00ff_ffff);
+        match below(state, 4) {
+            // Overlapping with another entry
+            0 => {
+                let locations = psp.locations();
+                locations[below(state, locations.len())]
+            }
+            // Overlapping with the directory itself
+            1 => mapped(self.layout.flash_offset(dir.offset).unwrap_or(0)),
+            // Out of the flash bounds
+            2 => match below(state, 3) {
+                0 => self.layout.flash_size + below(state, 0x1000) as u32,
+                1 => mapped(self.layout.flash_size - 1 - below(state, 0x100) as u32),
+                _ => INTERESTING_U32[below(state, INTERESTING_U32.len())],
+            },
+            // Slightly shifted
+            _ => old
+                .wrapping_add(below(state, 0x200) as u32)
+                .wrapping_sub(0x100),
+        }
+    }
+
+    fn size<S: HasRand>(&self, state: &mut S, location: u32, old: u32) -> u32 {
+        match below(state, 3) {
+            0 => INTERESTING_U32[below(state, INTERESTING_U32.len())],
+            // Just reaching over the end of the flash
+            1 => self
+                .layout
+                .flash_size
+                .wrapping_sub(location & 0x00ff_ffff)
+                .wrapping_add(below(state, 0x20) as u32),
+            _ => old
+                .wrapping_add(below(state, 0x200) as u32)
+                .wrapping_sub(0x100),
+        }
+    }
+}
+
+impl<S> Mutator<BytesInput, S> for PspEntryFieldMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        Ok(mutate_psp(&self.layout, input, |psp| {
+            let Some((dir, end)) = psp.choose_directory(state, 1) else {
+                return MutationResult::Skipped;
+            };
+            let idx = below(state, dir.entries(end));
+            let fields = dir.kind.entry_fields();
+            let (field, _) = fields[below(state, fields.len())];
+            let offset = dir.field_offset(idx, field).unwrap();
+            let old = read_u32(&psp.bytes, offset).unwrap();
+            let new = match field {
+                EntryField::Type => match below(state, 2) {
+                    0 => (old & !0xff) | below(state, 0x100) as u32,
+                    _ => INTERESTING_U32[below(state, INTERESTING_U32.len())],
+                },
+                EntryField::Size => {
+                    let location = dir
+                        .field_offset(idx, EntryField::Location)
+                        .and_then(|offset| read_u32(&psp.bytes, offset))
+                        .unwrap_or(0);
+                    self.size(state, location, old)
+                }
+                EntryField::Location | EntryField::Destination => {
+                    self.location(state, psp, &dir, old)
+                }
+            };
+            if new == old {
+                return MutationResult::Skipped;
+            }
+            write_u32(&mut psp.bytes, offset, new);
+            MutationResult::Mutated
+        }))
+    }
+}
+
+impl Named for PspEntryFieldMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("PspEntryFieldMutator")
+    }
+}
+
+/// Duplicates an entry of a directory at a random position
+#[derive(Debug)]
+pub struct PspEntryInsertMutator {
+    layout: PspLayout,
+    fix_count: bool,
+}
+
+impl PspEntryInsertMutator {
+    /// With `fix_count` the entry count of the directory is incremented
+    #[must_use]
+    pub fn new(layout: PspLayout, fix_count: bool) -> Self {
+        Self { layout, fix_count }
+    }
+}
+
+impl<S> Mutator<BytesInput, S> for PspEntryInsertMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        Ok(mutate_psp(&self.layout, input, |psp| {
+            let Some((mut dir, end)) = psp.choose_directory(state, 1) else {
+                return MutationResult::Skipped;
+            };
+            let entries = dir.entries(end);
+            let src = dir.entry_offset(below(state, entries));
+            let entry = psp.bytes[src..src + dir.kind.entry_size()].to_vec();
+            let idx = below(state, entries + 1);
+            if dir.insert_entry(&mut psp.bytes, end, idx, &entry, self.fix_count) {
+                MutationResult::Mutated
+            } else {
+                MutationResult::Skipped
+            }
+        }))
+    }
+}
+
+impl Named for PspEntryInsertMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("PspEntryInsertMutator")
+    }
+}
+
+/// Removes an entry from a directory
+#[derive(Debug)]
+pub struct PspEntryRemoveMutator {
+    layout: PspLayout,
+    fix_count: bool,
+}
+
+impl PspEntryRemoveMutator {
+    /// With `fix_count` the entry count of the directory is decremented
+    #[must_use]
+    pub fn new(layout: PspLayout, fix_count: bool) -> Self {
+        Self { layout, fix_count }
+    }
+}
+
+impl<S> Mutator<BytesInput, S> for PspEntryRemoveMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        Ok(mutate_psp(&self.layout, input, |psp| {
+            let Some((mut dir, end)) = psp.choose_directory(state, 1) else {
+                return MutationResult::Skipped;
+            };
+            let idx = below(state, dir.entries(end));
+            if dir.remove_entry(&mut psp.bytes, end, idx, self.fix_count) {
+                MutationResult::Mutated
+            } else {
+                MutationResult::Skipped
+            }
+        }))
+    }
+}
+
+impl Named for PspEntryRemoveMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("PspEntryRemoveMutator")
+    }
+}
+
+/// Swaps two entries of a directory
+#[derive(Debug)]
+pub struct PspEntrySwapMutator {
+    layout: PspLayout,
+}
+
+impl PspEntrySwapMutator {
+    #[must_use]
+    pub fn new(layout: PspLayout) -> Self {
+        Self { layout }
+    }
+}
+
+impl<S> Mutator<BytesInput, S> for PspEntrySwapMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        Ok(mutate_psp(&self.layout, input, |psp| {
+            let Some((dir, end)) = psp.choose_directory(state, 2) else {
+                return MutationResult::Skipped;
+            };
+            let entries = dir.entries(end);
+            let a = below(state, entries);
+            let b = below(state, entries);
+            if dir.swap_entries(&mut psp.bytes, end, a, b) {
+                MutationResult::Mutated
+            } else {
+                MutationResult::Skipped
+            }
+        }))
+    }
+}
+
+impl Named for PspEntrySwapMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("PspEntrySwapMutator")
+    }
+}
+
+/// Sets the entry count of a directory out of sync with its entries
+#[derive(Debug)]
+pub struct PspEntryCountMutator {
+    layout: PspLayout,
+}
+
+impl PspEntryCountMutator {
+    #[must_use]
+    pub fn new(layout: PspLayout) -> Self {
+        Self { layout }
+    }
+}
+
+impl<S> Mutator<BytesInput, S> for PspEntryCountMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut BytesInput) -> Result<MutationResult, Error> {
+        Ok(mutate_psp(&self.layout, input, |psp| {
+            let Some((mut dir, end)) = psp.choose_directory(state, 0) else {
+                return MutationResult::Skipped;
+            };
+            let num_entries = match below(state, 4) {
+                0 => dir.num_entries.wrapping_add(1),
+                1 => dir.num_entries.wrapping_sub(1),
+                // Reading past the region into the rest of the flash
+                2 => (dir.capacity(end) + 1 + below(state, 0x40)) as u32,
+                _ => INTERESTING_U32[below(state, INTERESTING_U32.len())],
+            };
+            if num_entries == dir.num_entries {
+                return MutationResult::Skipped;
+            }
+            dir.set_num_entries(&mut psp.bytes, num_entries);
+            MutationResult::Mutated
+        }))
+    }
+}
+
+impl Named for PspEntryCountMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &Cow::Borrowed("PspEntryCountMutator")
+    }
+}
+
+/// All PSP directory mutations, with `fix_count` keeping the entry counts
+/// consistent when adding or removing entries
+pub fn psp_mutations(
+    layout: PspLayout,
+    fix_count: bool,
+) -> tuple_list_type!(
+    PspEntryFieldMutator,
+    PspEntryInsertMutator,
+    PspEntryRemoveMutator,
+    PspEntrySwapMutator,
+    PspEntryCountMutator
+) {
+    tuple_list!(
+        PspEntryFieldMutator::new(layout.clone()),
+        PspEntryInsertMutator::new(layout.clone(), fix_count),
+        PspEntryRemoveMutator::new(layout.clone(), fix_count),
+        PspEntrySwapMutator::new(layout.clone()),
+        PspEntryCountMutator::new(layout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemConfig;
+    use libafl_bolts::rands::StdRand;
+
+    #[test]
+    fn layout_finds_directories() {
+        let conf = InputConfig {
+            initial: Vec::new(),
+            mem: vec![
+                MemConfig {
+                    addr: 0x20000,
+                    size: 0x40,
+                },
+                MemConfig {
+                    addr: 0xff29_9000,
+                    size: 0x40,
+                },
+            ],
+            fixed: Vec::new(),
+            checksums: Vec::new(),
+        };
+        let layout = PspLayout::new(&conf, 0x100_0000);
+        let mut input = vec![0xff; 0x80];
+        input[0x40..0x44].copy_from_slice(b"$PSP");
+        write_u32(&mut input, 0x48, 1);
+        let dirs = layout.directories(&input);
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].0.offset, 0x40);
+        assert_eq!(dirs[0].1, 0x80);
+        assert_eq!(layout.flash_offset(0x44), Some(0x29_9004));
+        assert_eq!(layout.flash_offset(0x80), None);
+        // Truncated inputs only contain partial regions
+        assert!(layout.directories(&input[..0x46]).is_empty());
+    }
+
+    struct TestState {
+        rand: StdRand,
+    }
+
+    impl HasRand for TestState {
+        type Rand = StdRand;
+        fn rand(&self) -> &StdRand {
+            &self.rand
+        }
+        fn rand_mut(&mut self) -> &mut StdRand {
+            &mut self.rand
+        }
+    }
+
+    /// A `$PSP` directory with two entries and room for a third one
+    fn directory_input() -> (PspLayout, Vec<u8>) {
+        let conf = InputConfig {
+            initial: Vec::new(),
+            mem: vec![MemConfig {
+                addr: 0xff02_0000,
+                size: 0x40,
+            }],
+            fixed: Vec::new(),
+            checksums: Vec::new(),
+        };
+        let mut input = vec![0xff; 0x40];
+        input[0..4].copy_from_slice(b"$PSP");
+        write_u32(&mut input, 0x4, 0x1234_5678);
+        write_u32(&mut input, 0x8, 2);
+        write_u32(&mut input, 0xc, 0);
+        for (idx, entry) in [[0x1, 0x100, 0xff02_1000, 0], [0x8, 0x200, 0xff02_2000, 0]]
+            .iter()
+            .enumerate()
+        {
+            for (i, value) in entry.iter().enumerate() {
+                write_u32(&mut input, 0x10 + idx * 0x10 + i * 4, *value);
+            }
+        }
+        (PspLayout::new(&conf, 0x100_0000), input)
+    }
+
+    /// Offsets of the 32-bit words that differ
+    fn changed_words(a: &[u8], b: &[u8]) -> Vec<usize> {
+        (0..a.len())
+            .step_by(4)
+            .filter(|&offset| a[offset..offset + 4] != b[offset..offset + 4])
+            .collect()
+    }
+
+    #[test]
+    fn psp_input_round_trip() {
+        let (layout, bytes) = directory_input();
+        let psp = PspInput::from_flash_bytes(&layout, &bytes);
+        assert_eq!(psp.directories.len(), 1);
+        assert_eq!(psp.directories[0].0.entries(psp.directories[0].1), 2);
+        assert_eq!(psp.locations(), vec![0xff02_1000, 0xff02_2000]);
+        assert_eq!(psp.to_flash_bytes(), bytes);
+    }
+
+    #[test]
+    fn field_mutator_changes_one_field() {
+        let (layout, bytes) = directory_input();
+        let mut mutator = PspEntryFieldMutator::new(layout);
+        let mut mutated = 0;
+        for seed in 0..200 {
+            let mut state = TestState {
+                rand: StdRand::with_seed(seed),
+            };
+            let mut input = BytesInput::new(bytes.clone());
+            if mutator.mutate(&mut state, &mut input).unwrap() == MutationResult::Skipped {
+                continue;
+            }
+            mutated += 1;
+            let changed = changed_words(&bytes, input.target_bytes().as_slice());
+            // Type, size or location of one of the two entries, the header stays
+            assert_eq!(changed.len(), 1);
+            assert!(
+                [0x10, 0x14, 0x18, 0x20, 0x24, 0x28].contains(&changed[0]),
+                "changed {:#x}",
+                changed[0]
+            );
+        }
+        assert!(mutated > 0);
+    }
+
+    #[test]
+    fn count_mutator_changes_the_count() {
+        let (layout, bytes) = directory_input();
+        let mut mutator = PspEntryCountMutator::new(layout);
+        let mut counts = Vec::new();
+        for seed in 0..200 {
+            let mut state = TestState {
+                rand: StdRand::with_seed(seed),
+            };
+            let mut input = BytesInput::new(bytes.clone());
+            if mutator.mutate(&mut state, &mut input).unwrap() == MutationResult::Skipped {
+                continue;
+            }
+            let mutated = input.target_bytes().as_slice().to_vec();
+            assert_eq!(changed_words(&bytes, &mutated), vec![0x8]);
+            counts.push(read_u32(&mutated, 0x8).unwrap());
+        }
+        assert!(!counts.is_empty());
+        assert!(!counts.contains(&2));
+        // Off by one and past the capacity of three entries
+        assert!(counts.contains(&1) && counts.contains(&3));
+        assert!(counts.iter().any(|&count| count > 3));
+    }
+}
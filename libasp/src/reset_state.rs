@@ -1,32 +1,53 @@
 /// Custom resetting of the state aka. snapshotting
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Range;
+use std::path::Path;
 
 use libafl_qemu::*;
 use log;
-use serde::Deserialize;
-use std::fs::File;
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::str::FromStr;
 use sys::SyxSnapshot;
 
-const SRAM_START: GuestAddr = 0x0;
-const LAZY_SRAM_SIZE: GuestAddr = 0x1300;
+use crate::crash_bucket::fnv1a;
+use crate::{
+    clear_dirty_pages, diff_words, read_banked, read_cp15, registered_devices, take_dirty_pages,
+    write_banked, write_cp15, Cp15Reg, Device, DeviceState, ExceptionHandler, MemoryConfig,
+};
 
 #[derive(Clone)]
 pub struct ResetState {
     saved: bool,
+    /// Name of the snapshot, also used for the QEMU snapshot
+    name: String,
     num_loads: usize,
     regs: Vec<u32>,
+    sram_start: GuestAddr,
+    lazy_size: GuestUsize,
     sram: Vec<u8>,
-    timer_count_0: u64,
-    timer_count_1: u64,
-    timer_control_0: u64,
-    timer_control_1: u64,
-    smn_slots: [u32; 32],
+    /// Registered PSP devices and their saved states
+    devices: Vec<Device>,
+    device_states: Vec<DeviceState>,
+    /// [`PERSISTED_CP15`] and the banked registers, only kept for a stored state
+    cp15: Vec<u32>,
+    banked: Vec<u32>,
     syx_snapshot: Option<*mut SyxSnapshot>,
 }
 
-#[derive(Default, Deserialize)]
+/// CP15 registers of a stored state in the order they are restored,
+/// SCTLR last as it may turn on the MMU
+const PERSISTED_CP15: [Cp15Reg; 6] = [
+    Cp15Reg::Dacr,
+    Cp15Reg::Ttbcr,
+    Cp15Reg::Ttbr0,
+    Cp15Reg::Ttbr1,
+    Cp15Reg::Vbar,
+    Cp15Reg::Sctlr,
+];
+
+#[derive(Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
 pub enum ResetLevel {
     /*
      *  Loading snapshot:
@@ -38,8 +59,7 @@ pub enum ResetLevel {
      *  Loading snapshot:
      *  - R0-R15, CPSR
      *  - SRAM slice
-     *      - Zen1 & Zen+ [0x3ED00, 0x40000]
-     *      - Zen2 & Zen3 [0x4ED00, 0x50000]
+     *      - top `memory.lazy_size` bytes (the stack)
      */
     #[default]
     Lazy,
@@ -48,210 +68,429 @@ pub enum ResetLevel {
      *  Loading snapshot:
      *  - R0-R15, CPSR
      *  - SRAM
-     *      - Zen1 & Zen+ [0x0, 0x40000]
-     *      - Zen2 & Zen3 [0x0, 0x50000]
-     *  - Timer
-     *  - SMN Control
+     *      - `memory.sram_size` bytes at `memory.sram_start`
+     *  - PSP devices (timers, SMN slots)
      */
     RustSnapshot,
 
     /*
-     * Qemu Native
+     *  Loading snapshot:
+     *  - R0-R15, CPSR
+     *  - SRAM pages written since the last load (write hooks)
+     *  - PSP devices
+     */
+    DirtyPages,
+
+    /*
+     *  Qemu native snapshot (savevm) of the whole machine
      */
     QemuSnapshot,
 
+    /*
+     *  Qemu fast snapshot (syx):
+     *  - CPU
+     *  - RAM pages written since the snapshot (QEMU dirty tracking)
+     *  - QEMU devices
+     *  - PSP devices
+     */
+    FastSnapshot,
+
     /*
      *  Resetting:
      *  - CPU
      *  - SRAM
-     *      - Zen1 & Zen+ [0x0, 0x40000]
-     *      - Zen2 & Zen3 [0x0, 0x50000]
-     *  - Timer
-     *  - SMN Control
+     *      - `memory.sram_size` bytes at `memory.sram_start`
+     *  - PSP devices
      *  Executing until until harness entry
      */
     HardReset,
 }
 
-extern "C" {
-    static mut aspfuzz_timer_count_0: u64;
-    static mut aspfuzz_timer_control_0: u64;
-    static mut aspfuzz_timer_count_1: u64;
-    static mut aspfuzz_timer_control_1: u64;
-    static mut aspfuzz_smn_slots: [u32; 32];
-    fn aspfuzz_smn_update_slot(idx: u32);
-}
-
 impl ResetState {
-    pub fn new(sram_size: GuestAddr) -> Self {
+    pub fn new(memory: &MemoryConfig) -> Self {
         Self {
             saved: false,
+            name: "start".to_string(),
             num_loads: 0,
             regs: vec![],
-            sram: vec![0; sram_size.try_into().unwrap()],
-            timer_count_0: 0,
-            timer_count_1: 0,
-            timer_control_0: 0,
-            timer_control_1: 0,
-            smn_slots: [0; 32],
+            sram_start: memory.sram_start,
+            lazy_size: memory.lazy_size,
+            sram: vec![0; memory.sram_size.try_into().unwrap()],
+            devices: registered_devices(),
+            device_states: vec![],
+            cp15: vec![],
+            banked: vec![],
             syx_snapshot: None,
         }
     }
 
-    fn save_full(&mut self, emu: &Qemu) {
-        log::info!("Saving full snapshot");
+    fn save_full(&mut self, emu: &Qemu) -> Result<(), ResetError> {
+        log::info!("Saving full snapshot {}", self.name);
 
         // Saving registers
+        self.regs.clear();
         for r in Regs::iter() {
-            self.regs.push(emu.read_reg(r).unwrap());
+            self.regs.push(
+                emu.read_reg(r)
+                    .map_err(|e| ResetError::Register(format!("{r:?}: {e:?}")))?,
+            );
         }
 
         // Saving SRAM
-        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+        let cpu = current_cpu(emu)?;
         unsafe {
-            cpu.read_mem(SRAM_START, &mut self.sram);
+            cpu.read_mem(self.sram_start, &mut self.sram);
         }
 
-        // Saving ASP timer state
-        unsafe {
-            self.timer_count_0 = aspfuzz_timer_count_0;
-            self.timer_count_1 = aspfuzz_timer_count_1;
-            self.timer_control_0 = aspfuzz_timer_control_0;
-            self.timer_control_1 = aspfuzz_timer_control_1;
+        // Saving PSP device state
+        self.device_states = self.devices.iter().map(Device::save).collect();
+        self.save_qemu(emu);
+        Ok(())
+    }
+
+    /* QEMU snapshots of the current state */
+    fn save_qemu(&mut self, emu: &Qemu) {
+        self.syx_snapshot = Some(emu.create_fast_snapshot(true));
+        emu.save_snapshot(&self.name, true);
+        clear_dirty_pages();
+    }
+
+    /// Saves the current state as the snapshot `name`, replacing the state saved before
+    pub fn save_named(&mut self, emu: &Qemu, name: &str) -> Result<(), ResetError> {
+        self.name = name.to_string();
+        self.saved = false;
+        self.save(emu, &ResetLevel::RustSnapshot)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// PC of the saved state
+    pub fn pc(&self) -> Option<GuestAddr> {
+        self.regs.get(Regs::Pc as usize).copied()
+    }
+
+    /// Writes the saved registers, SRAM and PSP devices to `dir`, together with CP15 and
+    /// the banked registers read from the emulator, so it has to be called right after the save
+    pub fn store(&self, emu: &Qemu, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let state = StoredState {
+            name: self.name.clone(),
+            regs: self.regs.clone(),
+            sram_start: self.sram_start,
+            sram_size: self.sram.len(),
+            devices: self.device_states.clone(),
+            cp15: read_cp15(emu, &PERSISTED_CP15),
+            banked: read_banked(emu),
+        };
+        // Several clients may store at once, every file is replaced as a whole
+        let pid = std::process::id();
+        let sram_tmp = dir.join(format!(".{STORED_SRAM}.{pid}"));
+        fs::write(&sram_tmp, &self.sram)?;
+        fs::rename(&sram_tmp, dir.join(STORED_SRAM))?;
+        let state_tmp = dir.join(format!(".{STORED_STATE}.{pid}"));
+        fs::write(&state_tmp, serde_json::to_string_pretty(&state).unwrap())?;
+        fs::rename(&state_tmp, dir.join(STORED_STATE))
+    }
+
+    /// Reads a state written by [`ResetState::store`], it has to match the `memory` geometry
+    pub fn from_dir(dir: &Path, memory: &MemoryConfig) -> Result<Self, String> {
+        let read = |name: &str| {
+            fs::read(dir.join(name)).map_err(|e| format!("{}: {e}", dir.join(name).display()))
+        };
+        let state: StoredState = serde_json::from_slice(&read(STORED_STATE)?)
+            .map_err(|e| format!("{}: {e}", dir.join(STORED_STATE).display()))?;
+        let sram = read(STORED_SRAM)?;
+        if state.sram_start != memory.sram_start
+            || state.sram_size != memory.sram_size as usize
+            || sram.len() != state.sram_size
+        {
+            return Err(format!(
+                "stored SRAM [{:#x}, +{:#x}) does not match the config [{:#x}, +{:#x})",
+                state.sram_start, state.sram_size, memory.sram_start, memory.sram_size
+            ));
+        }
+        let mut rs = Self::new(memory);
+        rs.name = state.name;
+        rs.regs = state.regs;
+        rs.sram = sram;
+        // The device states have to come from the same QEMU build
+        for stored in &state.devices {
+            match rs
+                .devices
+                .iter()
+                .find(|device| device.name() == stored.name)
+            {
+                Some(device) if device.size() == stored.state.len() => {}
+                Some(device) => {
+                    return Err(format!(
+                        "stored state of {} has {:#x} bytes, the device {:#x}",
+                        stored.name,
+                        stored.state.len(),
+                        device.size()
+                    ))
+                }
+                None => return Err(format!("stored device {} is not registered", stored.name)),
+            }
+        }
+        rs.device_states = state.devices;
+        if state.cp15.len() != PERSISTED_CP15.len() {
+            return Err(format!(
+                "stored state has {} CP15 registers instead of {}",
+                state.cp15.len(),
+                PERSISTED_CP15.len()
+            ));
         }
+        rs.cp15 = state.cp15;
+        rs.banked = state.banked;
+        Ok(rs)
+    }
 
-        // Saving SMN slot controller state
-        unsafe {
-            self.smn_slots = aspfuzz_smn_slots;
+    /// Puts a state read by [`ResetState::from_dir`] into the emulator and takes the QEMU
+    /// snapshots of it. A state the emulator does not take is refused, QEMU devices without
+    /// a stored state keep their reset state.
+    pub fn restore(&mut self, emu: &Qemu) -> Result<(), ResetError> {
+        if self.saved {
+            return Err(ResetError::AlreadySaved);
         }
-        self.syx_snapshot = Some(emu.create_fast_snapshot(true));
-        emu.save_snapshot("start", true);
+        log::info!("Restoring stored snapshot");
+        self.load_rust_snapshot(emu)?;
+        let current = read_cp15(emu, &PERSISTED_CP15);
+        let writes: Vec<(Cp15Reg, u32)> = PERSISTED_CP15
+            .iter()
+            .zip(self.cp15.iter().zip(&current))
+            .filter(|(_, (stored, current))| stored != current)
+            .map(|(&reg, (&stored, _))| (reg, stored))
+            .collect();
+        if !writes.is_empty() {
+            write_cp15(emu, &writes);
+        }
+        write_banked(emu, &self.banked);
+
+        let mut diverged: Vec<String> = PERSISTED_CP15
+            .iter()
+            .zip(read_cp15(emu, &PERSISTED_CP15).iter().zip(&self.cp15))
+            .filter(|(_, (current, stored))| current != stored)
+            .map(|(reg, (current, stored))| format!("{reg:?} is {current:#x}, not {stored:#x}"))
+            .collect();
+        if read_banked(emu) != self.banked {
+            diverged.push("banked registers".to_string());
+        }
+        for state in &self.device_states {
+            let device = self.devices.iter().find(|d| d.name() == state.name);
+            if device.is_some_and(|device| device.save().state != state.state) {
+                diverged.push(format!("device {}", state.name));
+            }
+        }
+        if !diverged.is_empty() {
+            return Err(ResetError::Diverged(diverged.join(", ")));
+        }
+        self.save_qemu(emu);
+        self.saved = true;
+        Ok(())
     }
 
     /* Super lazy reset */
-    fn load_super_lazy(&self, emu: &Qemu) {
+    fn load_super_lazy(&self, emu: &Qemu) -> Result<(), ResetError> {
         // Resetting registers
         for (r, v) in Regs::iter().zip(self.regs.iter()) {
-            emu.write_reg(r, *v).unwrap();
+            emu.write_reg(r, *v)
+                .map_err(|e| ResetError::Register(format!("{r:?}: {e:?}")))?;
         }
+        Ok(())
     }
 
     /* Lazy snapshot reset */
-    fn load_lazy(&self, emu: &Qemu) {
+    fn load_lazy(&self, emu: &Qemu) -> Result<(), ResetError> {
         log::info!("Loading lazy");
 
         // Resetting registers
-        self.load_super_lazy(emu);
+        self.load_super_lazy(emu)?;
 
         // Resetting SRAM (predefined section)
-        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
-        let sram_size: u32 = self.sram.len().try_into().unwrap();
-        let sram_slice = &self.sram[((sram_size - LAZY_SRAM_SIZE) as usize)..self.sram.len()];
+        let cpu = current_cpu(emu)?;
+        let lazy_offset = self.sram.len() - self.lazy_size as usize;
         unsafe {
-            cpu.write_mem(sram_size - LAZY_SRAM_SIZE, sram_slice);
+            cpu.write_mem(
+                self.sram_start + lazy_offset as GuestAddr,
+                &self.sram[lazy_offset..],
+            );
         }
+        Ok(())
     }
 
     /* Rust snapshot reset */
-    fn load_rust_snapshot(&self, emu: &Qemu) {
+    fn load_rust_snapshot(&self, emu: &Qemu) -> Result<(), ResetError> {
         log::info!("Loading Rust snapshot");
 
         // Resetting registers
-        self.load_super_lazy(emu);
+        self.load_super_lazy(emu)?;
 
         // Resetting SRAM
-        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+        let cpu = current_cpu(emu)?;
         unsafe {
-            cpu.write_mem(SRAM_START, &self.sram);
+            cpu.write_mem(self.sram_start, &self.sram);
         }
+        clear_dirty_pages();
 
-        // Resetting timer
-        unsafe {
-            if aspfuzz_timer_count_0 != self.timer_count_0 {
-                log::debug!("Timer count 0: resetting to {:#x}", self.timer_count_0);
-                aspfuzz_timer_count_0 = self.timer_count_0;
-            }
-            if aspfuzz_timer_count_1 != self.timer_count_1 {
-                log::debug!("Timer count 1: resetting to {:#x}", self.timer_count_1);
-                aspfuzz_timer_count_1 = self.timer_count_1;
-            }
-            if aspfuzz_timer_control_0 != self.timer_control_0 {
-                log::debug!("Timer control 0: resetting to {:#x}", self.timer_control_0);
-                aspfuzz_timer_control_0 = self.timer_control_0;
-            }
-            if aspfuzz_timer_control_1 != self.timer_control_1 {
-                log::debug!("Timer control 1: resetting to {:#x}", self.timer_control_1);
-                aspfuzz_timer_control_1 = self.timer_control_1;
+        self.load_devices();
+        Ok(())
+    }
+
+    /* Dirty page reset */
+    fn load_dirty_pages(&self, emu: &Qemu) -> Result<(), ResetError> {
+        log::info!("Loading dirty pages");
+
+        // Resetting registers
+        self.load_super_lazy(emu)?;
+
+        // Resetting the written SRAM pages
+        let cpu = current_cpu(emu)?;
+        for pages in take_dirty_pages() {
+            log::debug!("Restoring SRAM [{:#x}, {:#x})", pages.start, pages.end);
+            unsafe {
+                cpu.write_mem(
+                    self.sram_start + pages.start as GuestAddr,
+                    &self.sram[pages],
+                );
             }
         }
 
-        // Resetting SMN slot controller
-        let current_smn_slots;
-        unsafe {
-            current_smn_slots = aspfuzz_smn_slots;
-            aspfuzz_smn_slots = self.smn_slots;
-        }
-        for (i, (snapshot, current)) in current_smn_slots
-            .iter()
-            .zip(self.smn_slots.iter())
-            .enumerate()
-        {
-            if snapshot != current {
-                log::debug!("SMN slot {i} not correct anymore:");
-                log::debug!("\tsnapshot: {snapshot:#x}");
-                log::debug!("\tcurrent : {current:#x}");
-                unsafe { aspfuzz_smn_update_slot(i as u32) }
+        self.load_devices();
+        Ok(())
+    }
+
+    /* PSP devices of the snapshot */
+    fn load_devices(&self) {
+        for device in &self.devices {
+            if let Some(state) = self.device_states.iter().find(|s| s.name == device.name()) {
+                device.restore(state);
             }
         }
     }
 
     /* Qemu snapshot reset */
-    fn load_qemu_snapshot(&self, emu: &Qemu) {
-        emu.load_snapshot("start", true)
+    fn load_qemu_snapshot(&self, emu: &Qemu) -> Result<(), ResetError> {
+        emu.load_snapshot(&self.name, true);
+        clear_dirty_pages();
+        Ok(())
     }
 
-    /* Hard reset */
-    fn load_hard_reset(&self, emu: &Qemu) {
-        log::info!("Loading hard snapshot");
+    /* Fast (syx) snapshot reset, restores the RAM pages and devices QEMU saw change */
+    fn load_fast_snapshot(&self, emu: &Qemu) -> Result<(), ResetError> {
+        log::info!("Loading fast snapshot");
+        let snapshot = self.syx_snapshot.ok_or(ResetError::NoFastSnapshot)?;
+        unsafe {
+            emu.restore_fast_snapshot(snapshot);
+        }
+        // The PSP device state lives outside of the QEMU snapshot
+        self.load_devices();
+        clear_dirty_pages();
+        Ok(())
+    }
 
+    /// Puts the CPU, the SRAM and the PSP devices into their power-on state
+    pub fn power_on(&self, emu: &Qemu) -> Result<(), ResetError> {
         // Resetting CPU
         log::debug!("Starting CPU reset");
-        let cpu = emu.current_cpu().unwrap(); // ctx switch safe
+        let cpu = current_cpu(emu)?;
         cpu.reset();
         log::debug!("CPU reset successful");
 
         // Zero SRAM
         let zero_sram = vec![0; self.sram.len()];
         unsafe {
-            cpu.write_mem(SRAM_START, &zero_sram);
+            cpu.write_mem(self.sram_start, &zero_sram);
         }
 
-        // Zero timer
-        unsafe {
-            aspfuzz_timer_count_0 = 0;
-            aspfuzz_timer_count_1 = 0;
-            aspfuzz_timer_control_0 = 0;
-            aspfuzz_timer_control_1 = 0;
+        // Power-on state of the PSP devices
+        for device in &self.devices {
+            device.reset();
         }
+        Ok(())
+    }
 
-        // Zero SMN slots
-        unsafe {
-            aspfuzz_smn_slots = [0; 32];
-            for (i, _) in aspfuzz_smn_slots.iter().enumerate() {
-                aspfuzz_smn_update_slot(i as u32)
+    /* Hard reset */
+    fn load_hard_reset(
+        &self,
+        emu: &Qemu,
+        mut eh: Option<&mut ExceptionHandler>,
+    ) -> Result<(), ResetError> {
+        log::info!("Loading hard snapshot");
+        let start = self.pc().ok_or(ResetError::NotSaved)?;
+
+        self.power_on(emu)?;
+
+        // Run until fuzzing start address
+        emu.set_breakpoint(start);
+        let exit = loop {
+            let exit = unsafe { emu.run() };
+            // The boot relocated the exception vectors, without a handler the
+            // request stays pending for the next `rearm`
+            if ExceptionHandler::rearm_requested() {
+                if let Some(eh) = eh.as_mut() {
+                    eh.rearm(emu);
+                }
+                continue;
             }
+            break exit;
+        };
+        emu.remove_breakpoint(start);
+        let stopped = match exit {
+            Ok(QemuExitReason::Breakpoint(pc)) if pc == start => None,
+            Ok(reason) => Some(format!("{reason:?}")),
+            Err(e) => Some(format!("{e:?}")),
+        };
+        if let Some(stopped) = stopped {
+            return Err(ResetError::StartNotReached { start, stopped });
         }
+        log::debug!("After CPU reset: PC={:#x}", start);
+        Ok(())
+    }
 
-        // Run until fuzzing start address
-        emu.set_breakpoint(self.regs[Regs::Pc as usize] as GuestAddr);
-        unsafe {
-            // TODO return result here
-            emu.run().unwrap();
+    /// Compares the current state with the snapshot, done right after a reset
+    pub fn check(&self, emu: &Qemu) -> SnapshotDivergence {
+        let mut divergence = SnapshotDivergence::default();
+
+        // Registers
+        for (r, snapshot) in Regs::iter().zip(self.regs.iter()) {
+            let current: u32 = emu.read_reg(r).unwrap();
+            if current != *snapshot {
+                divergence
+                    .regs
+                    .push((format!("{r:?}"), u64::from(*snapshot), u64::from(current)));
+            }
         }
-        emu.remove_breakpoint(self.regs[Regs::Pc as usize] as GuestAddr);
+
+        // SRAM
+        let mut sram = vec![0; self.sram.len()];
         let cpu = emu.current_cpu().unwrap(); // ctx switch safe
-        let pc: u64 = cpu.read_reg(Regs::Pc).unwrap();
-        log::debug!("After CPU reset: PC={:#x}", pc);
+        unsafe {
+            cpu.read_mem(self.sram_start, &mut sram);
+        }
+        divergence.sram = diff_bytes(&self.sram, &sram)
+            .into_iter()
+            .map(|range| {
+                self.sram_start + range.start as GuestAddr..self.sram_start + range.end as GuestAddr
+            })
+            .collect();
+
+        // PSP devices
+        for device in &self.devices {
+            let Some(state) = self.device_states.iter().find(|s| s.name == device.name()) else {
+                continue;
+            };
+            let current = device.save();
+            for (offset, snapshot, current) in diff_words(&state.state, &current.state) {
+                divergence.devices.push((
+                    format!("{}+{offset:#x}", device.name()),
+                    u64::from(snapshot),
+                    u64::from(current),
+                ));
+            }
+        }
+        divergence
     }
 
     pub fn sram_to_file(&self) {
@@ -262,43 +501,203 @@ impl ResetState {
     pub fn current_sram_to_file(&mut self, emu: &Qemu) {
         let cpu = emu.current_cpu().unwrap(); // ctx switch safe
         unsafe {
-            cpu.write_mem(SRAM_START, &self.sram);
+            cpu.write_mem(self.sram_start, &self.sram);
         }
         let mut file = File::create("sram.dump").unwrap();
         file.write_all(&self.sram).unwrap();
     }
 }
 
+/// Byte ranges in which `current` differs from `snapshot`
+fn diff_bytes(snapshot: &[u8], current: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, _) in snapshot
+        .iter()
+        .zip(current.iter())
+        .enumerate()
+        .filter(|(_, (s, c))| s != c)
+    {
+        match ranges.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// State that a reset did not bring back to the snapshot
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotDivergence {
+    /// Register name, snapshot and current value
+    pub regs: Vec<(String, u64, u64)>,
+    /// Differing SRAM addresses
+    pub sram: Vec<Range<GuestAddr>>,
+    /// PSP device words (`device+offset`), snapshot and current value
+    pub devices: Vec<(String, u64, u64)>,
+}
+
+impl SnapshotDivergence {
+    pub fn is_empty(&self) -> bool {
+        self.regs.is_empty() && self.sram.is_empty() && self.devices.is_empty()
+    }
+
+    /// Stores the test-case run before the reset and the report in `dir`, returns their name
+    pub fn store(&self, dir: &Path, level: ResetLevel, input: &[u8]) -> String {
+        store_report(dir, level, input, &self.to_string())
+    }
+}
+
+/// Writes `input` and `report` as `<level>_<hash>` and `<level>_<hash>.txt` into `dir`
+fn store_report(dir: &Path, level: ResetLevel, input: &[u8], report: &str) -> String {
+    fs::create_dir_all(dir).unwrap();
+    let name = format!("{:?}_{:016x}", level, fnv1a(input));
+    fs::write(dir.join(&name), input).unwrap();
+    fs::write(dir.join(format!("{name}.txt")), report).unwrap();
+    name
+}
+
+impl Display for SnapshotDivergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for (name, snapshot, current) in self.regs.iter().chain(self.devices.iter()) {
+            writeln!(f, "{name}: {snapshot:#x} != {current:#x}")?;
+        }
+        if !self.sram.is_empty() {
+            let bytes: usize = self.sram.iter().map(|range| range.len()).sum();
+            writeln!(f, "SRAM: {bytes} bytes in {} ranges", self.sram.len())?;
+            for range in &self.sram {
+                writeln!(f, "\t[{:#010x}, {:#010x})", range.start, range.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const STORED_STATE: &str = "state.json";
+const STORED_SRAM: &str = "sram.bin";
+
+/// The parts of a [`ResetState`] written to disk, the SRAM is stored next to it
+#[derive(Serialize, Deserialize)]
+struct StoredState {
+    name: String,
+    regs: Vec<u32>,
+    sram_start: GuestAddr,
+    sram_size: usize,
+    devices: Vec<DeviceState>,
+    cp15: Vec<u32>,
+    banked: Vec<u32>,
+}
+
+/// Why saving or resetting the state failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResetError {
+    /// Loading before a state was saved
+    NotSaved,
+    /// Saving a second time, [`ResetState::save_named`] replaces a state
+    AlreadySaved,
+    /// QEMU has no current CPU
+    NoCpu,
+    /// Reading or writing a register failed
+    Register(String),
+    /// The fast snapshot of QEMU was not taken
+    NoFastSnapshot,
+    /// The hard reset stopped before it reached the PC of the snapshot
+    StartNotReached { start: GuestAddr, stopped: String },
+    /// The emulator did not take a stored state
+    Diverged(String),
+}
+
+impl Display for ResetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ResetError::NotSaved => write!(f, "no state has been saved"),
+            ResetError::AlreadySaved => write!(f, "the state has already been saved"),
+            ResetError::NoCpu => write!(f, "QEMU has no current CPU"),
+            ResetError::Register(e) => write!(f, "register access failed: {e}"),
+            ResetError::NoFastSnapshot => write!(f, "no fast snapshot has been taken"),
+            ResetError::StartNotReached { start, stopped } => {
+                write!(
+                    f,
+                    "hard reset never reached {start:#x}, stopped with {stopped}"
+                )
+            }
+            ResetError::Diverged(e) => write!(f, "the restored state diverges: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ResetError {}
+
+impl ResetError {
+    /// Stores the test-case run before the failed reset and the error in `dir`, returns their name
+    pub fn store(&self, dir: &Path, level: ResetLevel, input: &[u8]) -> String {
+        store_report(dir, level, input, &format!("{self}\n"))
+    }
+}
+
+fn current_cpu(emu: &Qemu) -> Result<CPU, ResetError> {
+    emu.current_cpu().ok_or(ResetError::NoCpu) // ctx switch safe
+}
+
 pub trait Reset {
-    fn save(&mut self, emu: &Qemu, level: &ResetLevel);
-    fn load(&mut self, emu: &Qemu, level: &ResetLevel);
+    fn save(&mut self, emu: &Qemu, level: &ResetLevel) -> Result<(), ResetError>;
+    fn load(&mut self, emu: &Qemu, level: &ResetLevel) -> Result<(), ResetError>;
 }
 
 impl Reset for ResetState {
-    fn save(&mut self, emu: &Qemu, level: &ResetLevel) {
+    fn save(&mut self, emu: &Qemu, level: &ResetLevel) -> Result<(), ResetError> {
         if self.saved {
-            log::error!("State has already been saved!");
-            return;
+            return Err(ResetError::AlreadySaved);
         }
         match level {
             ResetLevel::SuperLazy => self.save_full(emu),
             ResetLevel::Lazy => self.save_full(emu),
             ResetLevel::RustSnapshot => self.save_full(emu),
+            ResetLevel::DirtyPages => self.save_full(emu),
             ResetLevel::QemuSnapshot => self.save_full(emu),
+            ResetLevel::FastSnapshot => self.save_full(emu),
             ResetLevel::HardReset => self.save_full(emu),
-        };
+        }?;
         self.saved = true;
+        Ok(())
     }
 
-    fn load(&mut self, emu: &Qemu, level: &ResetLevel) {
+    fn load(&mut self, emu: &Qemu, level: &ResetLevel) -> Result<(), ResetError> {
+        self.load_level(emu, level, None)
+    }
+}
+
+impl ResetState {
+    /// [`Reset::load`] that moves the hooks of `eh` when a hard reset
+    /// relocates the exception vectors on its way to the start point
+    pub fn load_rearming(
+        &mut self,
+        emu: &Qemu,
+        level: &ResetLevel,
+        eh: &mut ExceptionHandler,
+    ) -> Result<(), ResetError> {
+        self.load_level(emu, level, Some(eh))
+    }
+
+    fn load_level(
+        &mut self,
+        emu: &Qemu,
+        level: &ResetLevel,
+        eh: Option<&mut ExceptionHandler>,
+    ) -> Result<(), ResetError> {
+        if !self.saved {
+            return Err(ResetError::NotSaved);
+        }
         match level {
             ResetLevel::SuperLazy => self.load_super_lazy(emu),
             ResetLevel::Lazy => self.load_lazy(emu),
             ResetLevel::RustSnapshot => self.load_rust_snapshot(emu),
+            ResetLevel::DirtyPages => self.load_dirty_pages(emu),
             ResetLevel::QemuSnapshot => self.load_qemu_snapshot(emu),
-            ResetLevel::HardReset => self.load_hard_reset(emu),
-        };
+            ResetLevel::FastSnapshot => self.load_fast_snapshot(emu),
+            ResetLevel::HardReset => self.load_hard_reset(emu, eh),
+        }?;
         self.num_loads += 1;
+        Ok(())
     }
 }
 
@@ -309,7 +708,9 @@ impl Debug for ResetLevel {
             ResetLevel::SuperLazy => out_str.push_str("SuperLazy"),
             ResetLevel::Lazy => out_str.push_str("Lazy"),
             ResetLevel::RustSnapshot => out_str.push_str("RustSnapshot"),
+            ResetLevel::DirtyPages => out_str.push_str("DirtyPages"),
             ResetLevel::QemuSnapshot => out_str.push_str("QemuSnapshot"),
+            ResetLevel::FastSnapshot => out_str.push_str("FastSnapshot"),
             ResetLevel::HardReset => out_str.push_str("HardReset"),
         }
         write!(f, "{}", out_str)
@@ -323,7 +724,9 @@ impl FromStr for ResetLevel {
             "SuperLazy" => Ok(ResetLevel::SuperLazy),
             "Lazy" => Ok(ResetLevel::Lazy),
             "RustSnapshot" => Ok(ResetLevel::RustSnapshot),
+            "DirtyPages" => Ok(ResetLevel::DirtyPages),
             "QemuSnapshot" => Ok(ResetLevel::QemuSnapshot),
+            "FastSnapshot" => Ok(ResetLevel::FastSnapshot),
             "HardReset" => Ok(ResetLevel::HardReset),
             _ => Err(()),
         }
@@ -336,8 +739,9 @@ impl Debug for ResetState {
 
         /* Stats to string */
         out_str.push_str(&format!(
-            "[{}]\n",
-            if self.saved { "INIT" } else { "UNINIT" }
+            "[{}] {}\n",
+            if self.saved { "INIT" } else { "UNINIT" },
+            self.name
         ));
         out_str.push_str("Stats:\n");
         out_str.push_str(&format!("\tLoads =\t{}\n", self.num_loads));
@@ -379,3 +783,42 @@ impl Debug for ResetState {
         write!(f, "{}", out_str)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diverging_bytes() {
+        let snapshot = [0, 1, 2, 3, 4, 5, 6, 7];
+        let current = [0, 9, 9, 3, 4, 5, 9, 7];
+        assert_eq!(diff_bytes(&snapshot, &current), vec![1..3, 6..7]);
+        assert!(diff_bytes(&snapshot, &snapshot).is_empty());
+
+        let divergence = SnapshotDivergence {
+            regs: vec![("Sp".to_string(), 0x4f000, 0x4eff0)],
+            sram: vec![0x100..0x102],
+            devices: vec![("smn_slots+0xc".to_string(), 0x4400, 0)],
+        };
+        assert_eq!(
+            divergence.to_string(),
+            "Sp: 0x4f000 != 0x4eff0\nsmn_slots+0xc: 0x4400 != 0x0\n\
+             SRAM: 2 bytes in 1 ranges\n\t[0x00000100, 0x00000102)\n"
+        );
+    }
+
+    #[test]
+    fn reset_levels() {
+        for level in ["QemuSnapshot", "FastSnapshot", "HardReset"] {
+            assert_eq!(format!("{:?}", ResetLevel::from_str(level).unwrap()), level);
+        }
+        let err = ResetError::StartNotReached {
+            start: 0xffff2c00,
+            stopped: "Breakpoint(0xffff05c0)".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "hard reset never reached 0xffff2c00, stopped with Breakpoint(0xffff05c0)"
+        );
+    }
+}
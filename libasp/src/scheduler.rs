@@ -0,0 +1,95 @@
+/// Choosing the corpus scheduler at runtime
+use libafl::prelude::*;
+
+/// One of two schedulers, so the scheduler can be picked from the config
+/// without changing the type of the fuzzer
+#[derive(Debug)]
+pub enum EitherScheduler<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> UsesState for EitherScheduler<A, B>
+where
+    A: UsesState,
+{
+    type State = A::State;
+}
+
+impl<A, B> Scheduler for EitherScheduler<A, B>
+where
+    A: Scheduler,
+    B: Scheduler<State = A::State>,
+    A::State: HasCorpus,
+{
+    fn on_add(&mut self, state: &mut Self::State, idx: CorpusId) -> Result<(), Error> {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.on_add(state, idx),
+            EitherScheduler::Right(scheduler) => scheduler.on_add(state, idx),
+        }
+    }
+
+    fn on_evaluation<OT>(
+        &mut self,
+        state: &mut Self::State,
+        input: &<Self::State as UsesInput>::Input,
+        observers: &OT,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<Self::State>,
+    {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.on_evaluation(state, input, observers),
+            EitherScheduler::Right(scheduler) => scheduler.on_evaluation(state, input, observers),
+        }
+    }
+
+    fn next(&mut self, state: &mut Self::State) -> Result<CorpusId, Error> {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.next(state),
+            EitherScheduler::Right(scheduler) => scheduler.next(state),
+        }
+    }
+
+    fn set_current_scheduled(
+        &mut self,
+        state: &mut Self::State,
+        next_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.set_current_scheduled(state, next_idx),
+            EitherScheduler::Right(scheduler) => scheduler.set_current_scheduled(state, next_idx),
+        }
+    }
+}
+
+impl<A, B> RemovableScheduler for EitherScheduler<A, B>
+where
+    A: RemovableScheduler,
+    B: RemovableScheduler<State = A::State>,
+    A::State: HasCorpus,
+{
+    fn on_remove(
+        &mut self,
+        state: &mut Self::State,
+        idx: CorpusId,
+        testcase: &Option<Testcase<<Self::State as UsesInput>::Input>>,
+    ) -> Result<(), Error> {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.on_remove(state, idx, testcase),
+            EitherScheduler::Right(scheduler) => scheduler.on_remove(state, idx, testcase),
+        }
+    }
+
+    fn on_replace(
+        &mut self,
+        state: &mut Self::State,
+        idx: CorpusId,
+        prev: &Testcase<<Self::State as UsesInput>::Input>,
+    ) -> Result<(), Error> {
+        match self {
+            EitherScheduler::Left(scheduler) => scheduler.on_replace(state, idx, prev),
+            EitherScheduler::Right(scheduler) => scheduler.on_replace(state, idx, prev),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+/// Resolving `symbol+offset` addresses in the YAML config against a symbol file
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use libafl_qemu::GuestAddr;
+use serde::{
+    de::{self, Unexpected, Visitor},
+    Deserialize, Deserializer,
+};
+
+/// Symbol names and addresses of a bootloader dump
+#[derive(Debug, Default, Clone)]
+pub struct SymbolMap {
+    symbols: HashMap<String, GuestAddr>,
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Fields of a CSV line, Ghidra quotes every field
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+impl SymbolMap {
+    /// Reads a Ghidra symbol table CSV export or an `nm` listing
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read symbol file {}: {e}", path.display()))?;
+        Ok(Self::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let lines = text.lines().filter(|line| !line.trim().is_empty());
+        let mut map = Self::default();
+        let Some(first) = lines.clone().next() else {
+            return map;
+        };
+        let header = csv_fields(first);
+        let name_col = header.iter().position(|h| h == "Name");
+        let location_col = header.iter().position(|h| h == "Location");
+        if let (Some(name_col), Some(location_col)) = (name_col, location_col) {
+            // Ghidra CSV, locations are hex, optionally prefixed by the address space
+            for line in lines.skip(1) {
+                let fields = csv_fields(line);
+                let (Some(name), Some(location)) = (fields.get(name_col), fields.get(location_col))
+                else {
+                    continue;
+                };
+                let location = location.rsplit(':').next().unwrap();
+                if let Ok(addr) = GuestAddr::from_str_radix(location, 16) {
+                    map.insert(name, addr);
+                }
+            }
+        } else {
+            // nm: `<address> <type> <name>`, undefined symbols have no address
+            for line in lines {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if let [addr, _, name, ..] = fields[..] {
+                    if let Ok(addr) = GuestAddr::from_str_radix(addr, 16) {
+                        map.insert(name, addr);
+                    }
+                }
+            }
+        }
+        map
+    }
+
+    fn insert(&mut self, name: &str, addr: GuestAddr) {
+        self.symbols.entry(name.to_string()).or_insert(addr);
+    }
+
+    pub fn get(&self, name: &str) -> Option<GuestAddr> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Resolves a number, `symbol`, `symbol+offset` or `symbol-offset`
+    pub fn resolve(&self, expr: &str) -> Result<GuestAddr, String> {
+        let expr = expr.trim();
+        if let Some(addr) = parse_number(expr) {
+            return GuestAddr::try_from(addr).map_err(|_| format!("{expr} is no 32-bit address"));
+        }
+        let sign = expr
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == '+' || *c == '-');
+        let (name, offset) = match sign {
+            Some((pos, sign)) => {
+                let value = parse_number(&expr[pos + 1..])
+                    .ok_or_else(|| format!("Invalid offset in `{expr}`"))?
+                    as i64;
+                let offset = if sign == '-' { -value } else { value };
+                (expr[..pos].trim(), offset)
+            }
+            None => (expr, 0),
+        };
+        let base = self.get(name).ok_or_else(|| {
+            if self.is_empty() {
+                format!("Unknown symbol `{name}`, no symbol file given in `qemu.symbols`")
+            } else {
+                format!("Unknown symbol `{name}`")
+            }
+        })?;
+        GuestAddr::try_from(i64::from(base) + offset)
+            .map_err(|_| format!("`{expr}` is no 32-bit address"))
+    }
+}
+
+thread_local! {
+    /// Symbols used while deserializing a config
+    static SYMBOLS: RefCell<SymbolMap> = RefCell::new(SymbolMap::default());
+}
+
+/// Runs `f`, typically the deserialization of a config, with `symbols` for the addresses
+pub fn with_symbols<R>(symbols: SymbolMap, f: impl FnOnce() -> R) -> R {
+    SYMBOLS.with(|s| *s.borrow_mut() = symbols);
+    let result = f();
+    SYMBOLS.with(|s| *s.borrow_mut() = SymbolMap::default());
+    result
+}
+
+/// An address given as a number or as `symbol+offset`
+struct AddrExpr(GuestAddr);
+
+impl<'de> Deserialize<'de> for AddrExpr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AddrVisitor;
+        impl<'de> Visitor<'de> for AddrVisitor {
+            type Value = GuestAddr;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a 32-bit address or `symbol+offset`")
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<GuestAddr, E>
+            where
+                E: de::Error,
+            {
+                GuestAddr::try_from(value)
+                    .map_err(|_| de::Error::invalid_value(Unexpected::Unsigned(value), &self))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<GuestAddr, E>
+            where
+                E: de::Error,
+            {
+                GuestAddr::try_from(value)
+                    .map_err(|_| de::Error::invalid_value(Unexpected::Signed(value), &self))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<GuestAddr, E>
+            where
+                E: de::Error,
+            {
+                SYMBOLS
+                    .with(|symbols| symbols.borrow().resolve(value))
+                    .map_err(de::Error::custom)
+            }
+        }
+        deserializer.deserialize_any(AddrVisitor).map(AddrExpr)
+    }
+}
+
+pub fn deserialize_addr<'de, D>(deserializer: D) -> Result<GuestAddr, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    AddrExpr::deserialize(deserializer).map(|addr| addr.0)
+}
+
+pub fn deserialize_opt_addr<'de, D>(deserializer: D) -> Result<Option<GuestAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<AddrExpr>::deserialize(deserializer).map(|addr| addr.map(|addr| addr.0))
+}
+
+pub fn deserialize_addrs<'de, D>(deserializer: D) -> Result<Vec<GuestAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<AddrExpr>::deserialize(deserializer)
+        .map(|addrs| addrs.into_iter().map(|addr| addr.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ghidra_csv() {
+        let symbols = SymbolMap::parse(
+            "\"Name\",\"Location\",\"Type\",\"Namespace\",\"Source\"\n\
+             \"on_chip_bl_main\",\"ffff2c00\",\"Function\",\"Global\",\"User Defined\"\n\
+             \"svc_handler\",\"ram:ffff731c\",\"Function\",\"Global\",\"User Defined\"\n\
+             \"printf\",\"EXTERNAL:00000001\",\"Function\",\"Global\",\"Imported\"\n",
+        );
+        assert_eq!(symbols.get("on_chip_bl_main"), Some(0xffff2c00));
+        assert_eq!(symbols.get("svc_handler"), Some(0xffff731c));
+    }
+
+    #[test]
+    fn parse_nm() {
+        let symbols = SymbolMap::parse(
+            "ffff24b8 T on_chip_bl_fletcher32\n         U memcpy\nffff2c00 t main\n",
+        );
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.get("on_chip_bl_fletcher32"), Some(0xffff24b8));
+        assert_eq!(symbols.get("memcpy"), None);
+    }
+
+    #[test]
+    fn resolve_expressions() {
+        let symbols = SymbolMap::parse("ffff2c00 T main\n");
+        assert_eq!(symbols.resolve("main"), Ok(0xffff2c00));
+        assert_eq!(symbols.resolve("main+0x10"), Ok(0xffff2c10));
+        assert_eq!(symbols.resolve("main - 4"), Ok(0xffff2bfc));
+        assert_eq!(symbols.resolve("0x1000"), Ok(0x1000));
+        assert!(symbols.resolve("missing+4").is_err());
+        assert!(SymbolMap::default().resolve("main").is_err());
+    }
+
+    #[test]
+    fn deserialize_with_symbols() {
+        #[derive(Deserialize, Debug)]
+        struct Conf {
+            #[serde(deserialize_with = "deserialize_addr")]
+            start: GuestAddr,
+            #[serde(deserialize_with = "deserialize_addrs")]
+            sinks: Vec<GuestAddr>,
+        }
+        let symbols = SymbolMap::parse("ffff2c00 T main\nffff3000 T fail\n");
+        let conf: Conf = with_symbols(symbols, || {
+            serde_yaml::from_str("start: main+0x4\nsinks:\n  - fail\n  - 0xffff4000\n").unwrap()
+        });
+        assert_eq!(conf.start, 0xffff2c04);
+        assert_eq!(conf.sinks, vec![0xffff3000, 0xffff4000]);
+        let err = serde_yaml::from_str::<Conf>("start: main\nsinks: []\n").unwrap_err();
+        assert!(err.to_string().contains("qemu.symbols"));
+    }
+}
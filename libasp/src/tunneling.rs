@@ -1,4 +1,7 @@
+use std::collections::BTreeMap;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 use libafl::inputs::UsesInput;
 use libafl_qemu::*;
@@ -8,6 +11,8 @@ use serde::{
     Deserialize, Deserializer,
 };
 
+use crate::{deserialize_addr, injecting};
+
 #[derive(Clone, Deserialize, Debug)]
 #[serde(tag = "action")]
 
@@ -24,7 +29,9 @@ pub enum CmpAction {
         value: GuestReg,
     },
     Jump {
+        #[serde(deserialize_with = "deserialize_addr")]
         source: GuestAddr,
+        #[serde(deserialize_with = "deserialize_addr")]
         target: GuestAddr,
     },
     LogRegister {
@@ -32,12 +39,14 @@ pub enum CmpAction {
         target: Regs,
     },
     WriteMemory {
+        #[serde(deserialize_with = "deserialize_addr")]
         target: GuestAddr,
         value: Vec<u8>,
     },
 }
 #[derive(Clone, Deserialize, Debug)]
 pub struct TunnelActions {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub addr: GuestAddr,
     #[serde(flatten)]
     pub value: CmpAction,
@@ -49,6 +58,30 @@ pub struct TunnelConfig {
     pub actions: Vec<TunnelActions>,
 }
 
+/// Number of times each tunnel address was hit, only counted while replaying
+static TUNNEL_HITS: Mutex<BTreeMap<GuestAddr, u64>> = Mutex::new(BTreeMap::new());
+static COUNT_TUNNEL_HITS: AtomicBool = AtomicBool::new(false);
+
+fn count_hit(addr: GuestAddr) {
+    if COUNT_TUNNEL_HITS.load(Ordering::Relaxed) {
+        *TUNNEL_HITS.lock().unwrap().entry(addr).or_default() += 1;
+    }
+}
+
+/// Enables counting the tunnel hits, off while fuzzing to keep the lock out of the hooks
+pub fn count_tunnel_hits(enabled: bool) {
+    COUNT_TUNNEL_HITS.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns how often each tunnel was hit since the last [`reset_tunnel_hits`]
+pub fn tunnel_hits() -> BTreeMap<GuestAddr, u64> {
+    TUNNEL_HITS.lock().unwrap().clone()
+}
+
+pub fn reset_tunnel_hits() {
+    TUNNEL_HITS.lock().unwrap().clear();
+}
+
 impl TunnelConfig {
     pub fn setup<QT, S>(&self, hooks: &QemuHooks<QT, S>)
     where
@@ -64,6 +97,10 @@ impl TunnelConfig {
                 CmpAction::SetConstant { target, value } => hooks.instruction(
                     addr,
                     Hook::Closure(Box::new(move |hks: &mut QemuHooks<QT, S>, _state, _pc| {
+                        if injecting() {
+                            return;
+                        }
+                        count_hit(addr);
                         log::debug!(
                             "Tunnel - Constant [{:#x}, {:?}, {:#x}]",
                             addr,
@@ -77,6 +114,10 @@ impl TunnelConfig {
                 CmpAction::CopyRegister { target, source } => hooks.instruction(
                     addr,
                     Hook::Closure(Box::new(move |hks: &mut QemuHooks<QT, S>, _state, _pc| {
+                        if injecting() {
+                            return;
+                        }
+                        count_hit(addr);
                         log::debug!(
                             "Tunnel - Register [{:#x}, {:?}, {:?}]",
                             addr,
@@ -92,6 +133,10 @@ impl TunnelConfig {
                 CmpAction::Jump { source, target } => hooks.instruction(
                     addr,
                     Hook::Closure(Box::new(move |hks: &mut QemuHooks<QT, S>, _state, _pc| {
+                        if injecting() {
+                            return;
+                        }
+                        count_hit(addr);
                         log::debug!("Tunnel - Jump [{:#x},{:#x}, {:#x}]", addr, source, target);
                         let inst: [u8; 2] = generate_branch_call(source, target);
                         // Patch the instruction by overwriting it
@@ -103,6 +148,10 @@ impl TunnelConfig {
                 CmpAction::LogRegister { target } => hooks.instruction(
                     addr,
                     Hook::Closure(Box::new(move |hks: &mut QemuHooks<QT, S>, _state, _pc| {
+                        if injecting() {
+                            return;
+                        }
+                        count_hit(addr);
                         let value: u32 = hks.qemu().read_reg(target).unwrap();
                         log::debug!("Tunnel - Log [{:#x}, {:?}, {:#x}]", addr, target, value);
                     })),
@@ -114,6 +163,10 @@ impl TunnelConfig {
                 } => hooks.instruction(
                     addr,
                     Hook::Closure(Box::new(move |hks: &mut QemuHooks<QT, S>, _state, _pc| {
+                        if injecting() {
+                            return;
+                        }
+                        count_hit(addr);
                         log::debug!(
                             "Tunnel - WriteMem [{:#x}, {:#x}, {:?}]",
                             addr,
@@ -0,0 +1,492 @@
+/// Checking the YAML config before the emulator is started
+use std::fmt;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use libafl_qemu::GuestAddr;
+
+use crate::{flash_offset, ResetLevel, YAMLConfig, MAX_TRACKED_SIZE};
+
+/// Address the on-chip bootloader is loaded to
+pub const ON_CHIP_BL_ADDR: GuestAddr = 0xffff_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// The file is no valid YAML or does not match the config structure
+    Parse,
+    /// A referenced file does not exist
+    MissingFile,
+    /// A flash range does not fit into `flash.size`
+    OutsideFlash,
+    /// Two `input.mem` regions share bytes
+    OverlappingRegions,
+    /// A `fixed` value overwrites bytes of the input
+    FixedInInput,
+    /// A sink or crash breakpoint is `harness.start`, so the harness stops right away
+    StartIsExit,
+    /// An address is not inside the loaded on-chip bootloader
+    OutsideRom,
+    /// A value is outside of its allowed range
+    InvalidValue,
+}
+
+/// A problem with the config and where in the YAML it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    /// YAML path of the offending value, e.g. `input.mem[1].size`
+    pub path: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(kind: DiagnosticKind, path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            kind,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(kind: DiagnosticKind, path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(kind, path, message)
+        }
+    }
+
+    /// Diagnostic for a YAML file that cannot be deserialized
+    pub fn parse(config_file: &Path, error: impl fmt::Display) -> Self {
+        Self::error(
+            DiagnosticKind::Parse,
+            config_file.display().to_string(),
+            error.to_string(),
+        )
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.message)
+    }
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl YAMLConfig {
+    /// Semantic checks of the config, file paths are relative to the working directory
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        use DiagnosticKind::*;
+        let mut diagnostics = Vec::new();
+        let flash_size = self.flash.size as usize;
+
+        // Referenced files
+        let mut files = vec![
+            (
+                "qemu.on_chip_bl_path".to_string(),
+                &self.qemu.on_chip_bl_path,
+            ),
+            ("flash.base".to_string(), &self.flash.base),
+        ];
+        for (i, initial) in self.input.initial.iter().enumerate() {
+            files.push((format!("input.initial[{i}]"), initial));
+        }
+        if let Some(symbols) = &self.qemu.symbols {
+            files.push(("qemu.symbols".to_string(), symbols));
+        }
+        if let Some(tokens) = &self.fuzzer.tokens {
+            files.push(("fuzzer.tokens".to_string(), tokens));
+        }
+        for (path, file) in files {
+            if !file.exists() {
+                diagnostics.push(Diagnostic::error(
+                    MissingFile,
+                    path,
+                    format!("{} does not exist", file.display()),
+                ));
+            }
+        }
+        if let Ok(base) = File::open(&self.flash.base) {
+            let len = base.metadata().unwrap().len();
+            if len != flash_size as u64 {
+                diagnostics.push(Diagnostic::error(
+                    OutsideFlash,
+                    "flash.size",
+                    format!(
+                        "{:#x} differs from the size of {} ({:#x})",
+                        flash_size,
+                        self.flash.base.display(),
+                        len
+                    ),
+                ));
+            }
+        }
+
+        // Fuzzed regions
+        let regions: Vec<Range<usize>> = self
+            .input
+            .mem
+            .iter()
+            .map(|mem| flash_offset(mem.addr)..flash_offset(mem.addr) + mem.size)
+            .collect();
+        for (i, region) in regions.iter().enumerate() {
+            if region.is_empty() {
+                diagnostics.push(Diagnostic::warning(
+                    OutsideFlash,
+                    format!("input.mem[{i}].size"),
+                    "region is empty",
+                ));
+            }
+            if region.end > flash_size {
+                diagnostics.push(Diagnostic::error(
+                    OutsideFlash,
+                    format!("input.mem[{i}]"),
+                    format!(
+                        "region {:#x}..{:#x} ends outside of the flash (size {:#x})",
+                        region.start, region.end, flash_size
+                    ),
+                ));
+            }
+            for (j, other) in regions.iter().enumerate().skip(i + 1) {
+                if overlaps(region, other) {
+                    diagnostics.push(Diagnostic::error(
+                        OverlappingRegions,
+                        format!("input.mem[{j}]"),
+                        format!(
+                            "region {:#x}..{:#x} overlaps input.mem[{i}] ({:#x}..{:#x})",
+                            other.start, other.end, region.start, region.end
+                        ),
+                    ));
+                }
+            }
+        }
+
+        // Fixed values
+        for (i, fixed) in self.input.fixed.iter().enumerate() {
+            let offset = flash_offset(fixed.addr);
+            let range = offset..offset + 4;
+            if range.end > flash_size {
+                diagnostics.push(Diagnostic::error(
+                    OutsideFlash,
+                    format!("input.fixed[{i}].addr"),
+                    format!("{:#x} is outside of the flash", fixed.addr),
+                ));
+            }
+            if let Some(j) = regions.iter().position(|region| overlaps(region, &range)) {
+                diagnostics.push(Diagnostic::warning(
+                    FixedInInput,
+                    format!("input.fixed[{i}].addr"),
+                    format!(
+                        "{:#x} overwrites fuzzed bytes of input.mem[{j}]",
+                        fixed.addr
+                    ),
+                ));
+            }
+        }
+        for (i, checksum) in self.input.checksums.iter().enumerate() {
+            if flash_offset(checksum.field) + 4 > flash_size {
+                diagnostics.push(Diagnostic::error(
+                    OutsideFlash,
+                    format!("input.checksums[{i}].field"),
+                    format!("{:#x} is outside of the flash", checksum.field),
+                ));
+            }
+        }
+
+        // Harness
+        for (i, sink) in self.harness.sinks.iter().enumerate() {
+            if *sink == self.harness.start {
+                diagnostics.push(Diagnostic::error(
+                    StartIsExit,
+                    format!("harness.sinks[{i}]"),
+                    format!("{:#x} is also harness.start", sink),
+                ));
+            }
+        }
+        for (i, bp) in self.crashes.breakpoints.iter().enumerate() {
+            if *bp == self.harness.start {
+                diagnostics.push(Diagnostic::error(
+                    StartIsExit,
+                    format!("crashes.breakpoints[{i}]"),
+                    format!("{:#x} is also harness.start", bp),
+                ));
+            }
+        }
+
+        if self.harness.timeout_ms == 0 {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "harness.timeout_ms",
+                "the timeout has to be at least 1 ms",
+            ));
+        }
+        if self.harness.max_blocks == Some(0) {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "harness.max_blocks",
+                "every execution would hang, omit the key for no budget",
+            ));
+        }
+        if self.snapshot.check_period == Some(0) {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "snapshot.check_period",
+                "omit the key to disable the check",
+            ));
+        }
+        for (i, point) in self.snapshot.points.iter().enumerate() {
+            if self.snapshot.points[..i]
+                .iter()
+                .any(|other| other.name == point.name)
+            {
+                diagnostics.push(Diagnostic::error(
+                    InvalidValue,
+                    format!("snapshot.points[{i}].name"),
+                    format!("`{}` is declared twice", point.name),
+                ));
+            }
+        }
+        if let Some(name) = &self.snapshot.start_point {
+            match self.snapshot.start_point() {
+                None => diagnostics.push(Diagnostic::error(
+                    InvalidValue,
+                    "snapshot.start_point",
+                    format!("`{name}` is not declared in snapshot.points"),
+                )),
+                Some(point) if self.harness.sinks.contains(&point.addr) => {
+                    diagnostics.push(Diagnostic::error(
+                        StartIsExit,
+                        "snapshot.start_point",
+                        format!("`{name}` at {:#x} is also a sink", point.addr),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.memory.sram_size == 0 {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "memory.sram_size",
+                "the snapshots need a non-empty SRAM",
+            ));
+        }
+        if self.snapshot.uses(ResetLevel::DirtyPages) && self.memory.sram_size > MAX_TRACKED_SIZE {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "memory.sram_size",
+                format!(
+                    "DirtyPages tracks at most {:#x} bytes of SRAM",
+                    MAX_TRACKED_SIZE
+                ),
+            ));
+        }
+        if self.memory.lazy_size > self.memory.sram_size {
+            diagnostics.push(Diagnostic::error(
+                InvalidValue,
+                "memory.lazy_size",
+                format!(
+                    "{:#x} is larger than the SRAM ({:#x})",
+                    self.memory.lazy_size, self.memory.sram_size
+                ),
+            ));
+        }
+
+        // Mailbox harness mode
+        if let Some(mailbox) = &self.mailbox {
+            if mailbox.idle == self.harness.start {
+                diagnostics.push(Diagnostic::error(
+                    StartIsExit,
+                    "mailbox.idle",
+                    format!("{:#x} is also harness.start", mailbox.idle),
+                ));
+            }
+            if self.harness.sinks.contains(&mailbox.idle) {
+                diagnostics.push(Diagnostic::error(
+                    InvalidValue,
+                    "mailbox.idle",
+                    format!(
+                        "{:#x} is also a sink, only the first command would be sent",
+                        mailbox.idle
+                    ),
+                ));
+            }
+            if mailbox.max_commands == 0 {
+                diagnostics.push(Diagnostic::error(
+                    InvalidValue,
+                    "mailbox.max_commands",
+                    "an input needs at least one command",
+                ));
+            }
+            if mailbox.max_payload > usize::from(u16::MAX) {
+                diagnostics.push(Diagnostic::error(
+                    InvalidValue,
+                    "mailbox.max_payload",
+                    format!(
+                        "the payload length is 16 bits, {:#x} is too large",
+                        mailbox.max_payload
+                    ),
+                ));
+            }
+            if mailbox.buffer.is_none() && mailbox.max_payload > 0 {
+                diagnostics.push(Diagnostic::warning(
+                    InvalidValue,
+                    "mailbox.max_payload",
+                    "without mailbox.buffer the payloads are dropped",
+                ));
+            }
+            if self.fuzzer.psp_mutations {
+                diagnostics.push(Diagnostic::warning(
+                    InvalidValue,
+                    "fuzzer.psp_mutations",
+                    "mailbox commands hold no PSP directories",
+                ));
+            }
+        }
+
+        // Tunnels are usually placed in the on-chip bootloader
+        if let Ok(rom) = File::open(&self.qemu.on_chip_bl_path) {
+            let rom_len = rom.metadata().unwrap().len();
+            let rom_end = u64::from(ON_CHIP_BL_ADDR) + rom_len;
+            for (i, tunnel) in self.tunnels.actions.iter().enumerate() {
+                let addr = u64::from(tunnel.addr);
+                if addr < u64::from(ON_CHIP_BL_ADDR) || addr >= rom_end {
+                    diagnostics.push(Diagnostic::warning(
+                        OutsideRom,
+                        format!("tunnels[{i}].addr"),
+                        format!(
+                            "{:#x} is outside of the on-chip bootloader ({:#x}..{:#x})",
+                            tunnel.addr, ON_CHIP_BL_ADDR, rom_end
+                        ),
+                    ));
+                }
+            }
+        }
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MailboxConfig, MemConfig};
+    use std::path::PathBuf;
+
+    fn zen2() -> YAMLConfig {
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zen2_desktop_parse_asp_flash.yaml")).unwrap()
+    }
+
+    fn kinds(diagnostics: &[Diagnostic]) -> Vec<DiagnosticKind> {
+        diagnostics
+            .iter()
+            .filter(|d| d.kind != DiagnosticKind::MissingFile)
+            .map(|d| d.kind)
+            .collect()
+    }
+
+    #[test]
+    fn shipped_config_is_valid() {
+        let diagnostics = zen2().validate();
+        assert!(kinds(&diagnostics)
+            .iter()
+            .all(|kind| *kind == DiagnosticKind::FixedInInput));
+    }
+
+    #[test]
+    fn broken_regions() {
+        let mut conf = zen2();
+        conf.input.mem.push(MemConfig {
+            addr: 0x000c0100,
+            size: 0x10,
+        });
+        conf.input.mem.push(MemConfig {
+            addr: 0x00fffff0,
+            size: 0x20,
+        });
+        conf.harness.sinks.push(conf.harness.start);
+        conf.harness.max_blocks = Some(0);
+        conf.memory.lazy_size = conf.memory.sram_size + 1;
+        conf.snapshot.check_period = Some(0);
+        conf.snapshot.start_point = Some("missing".to_string());
+        let diagnostics = conf.validate();
+        let overlap = diagnostics
+            .iter()
+            .find(|d| d.kind == DiagnosticKind::OverlappingRegions)
+            .unwrap();
+        assert_eq!(overlap.path, "input.mem[4]");
+        assert!(overlap.is_error());
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::OutsideFlash && d.path == "input.mem[5]"));
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::StartIsExit));
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::InvalidValue && d.path == "harness.max_blocks"));
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::InvalidValue && d.path == "memory.lazy_size"));
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::InvalidValue && d.path == "snapshot.check_period"));
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.kind == DiagnosticKind::InvalidValue && d.path == "snapshot.start_point"));
+    }
+
+    #[test]
+    fn broken_mailbox() {
+        let mut conf = zen2();
+        conf.mailbox = Some(MailboxConfig {
+            regs: 0x0301_0570,
+            idle: conf.harness.sinks[0],
+            buffer: None,
+            buffer_ptr: 0,
+            max_commands: 0,
+            max_payload: 0x10000,
+            initial: vec![],
+        });
+        let paths: Vec<String> = conf
+            .validate()
+            .into_iter()
+            .filter(|d| d.kind == DiagnosticKind::InvalidValue && d.is_error())
+            .map(|d| d.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "mailbox.idle",
+                "mailbox.max_commands",
+                "mailbox.max_payload"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let diagnostic = YAMLConfig::load(&d.join("Cargo.toml")).unwrap_err();
+        assert_eq!(diagnostic.kind, DiagnosticKind::Parse);
+        assert!(diagnostic.to_string().starts_with("error: "));
+    }
+}
@@ -1,12 +1,18 @@
 use crate::reset_state::ResetLevel;
-use crate::TunnelConfig;
+use crate::{
+    deserialize_addr, deserialize_addrs, deserialize_opt_addr, with_symbols, Diagnostic, SymbolMap,
+    TunnelConfig,
+};
 /// Parsing the YAML config file
 use libafl_qemu::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use sys::GuestUsize;
 
-use std::fs::File;
+use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::OnceLock;
 
 static CONF: OnceLock<RunConfig> = OnceLock::new();
@@ -17,6 +23,8 @@ pub struct RunConfig {
     pub num_cores: u32,
     pub run_dir: PathBuf,
     pub config_path: PathBuf,
+    /// `--set` overrides applied on top of the YAML file
+    pub overrides: Vec<ConfigOverride>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -37,33 +45,85 @@ impl ZenVersion {
             ZenVersion::ZenPlus => "amd-psp-zen+",
             ZenVersion::Zen2 => "amd-psp-zen2",
             ZenVersion::Zen3 => "amd-psp-zen3",
-            ZenVersion::Zen4 => panic!("Zen4 is currently not supported"),
+            ZenVersion::Zen4 => "amd-psp-zen4",
             ZenVersion::ZenTesla => "amd-psp-zentesla",
         }
     }
+
+    /// Size of the ASP's SRAM, mapped at address 0
+    pub fn sram_size(&self) -> GuestAddr {
+        match self {
+            ZenVersion::Zen1 | ZenVersion::ZenPlus | ZenVersion::ZenTesla => 0x4_0000,
+            ZenVersion::Zen2 | ZenVersion::Zen3 => 0x5_0000,
+            ZenVersion::Zen4 => 0x6_0000,
+        }
+    }
+
+    /// SRAM geometry of the generation
+    pub fn memory(&self) -> MemoryConfig {
+        MemoryConfig {
+            sram_start: 0x0,
+            sram_size: self.sram_size(),
+            lazy_size: 0x1300,
+        }
+    }
+
+    /// Values for the `flash` and `memory` keys a config leaves out
+    pub fn defaults(&self) -> [(&'static str, &'static str, GuestAddr); 6] {
+        let start_smn = match self {
+            ZenVersion::Zen1 | ZenVersion::ZenPlus | ZenVersion::ZenTesla => 0x0a00_0000,
+            ZenVersion::Zen2 | ZenVersion::Zen3 | ZenVersion::Zen4 => 0x4400_0000,
+        };
+        let memory = self.memory();
+        [
+            ("flash", "start_smn", start_smn),
+            ("flash", "size", 0x0100_0000),
+            ("flash", "start_cpu", 0x0200_0000),
+            ("memory", "sram_start", memory.sram_start),
+            ("memory", "sram_size", memory.sram_size),
+            ("memory", "lazy_size", memory.lazy_size),
+        ]
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct QemuConf {
     pub zen: ZenVersion,
     pub on_chip_bl_path: PathBuf,
+    /// Ghidra CSV export or `nm` listing of the bootloader, for `symbol+offset` addresses
+    #[serde(default)]
+    pub symbols: Option<PathBuf>,
 }
 #[derive(Deserialize, Debug)]
 pub struct FlashConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub start_smn: GuestAddr,
     pub size: GuestUsize,
+    #[serde(deserialize_with = "deserialize_addr")]
     pub start_cpu: GuestAddr,
     pub base: PathBuf,
 }
 
+/// SRAM of the ASP as restored by the snapshots, defaults to the one of `qemu.zen`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemoryConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub sram_start: GuestAddr,
+    pub sram_size: GuestUsize,
+    /// Top of the SRAM (the stack) restored by the `Lazy` reset level
+    pub lazy_size: GuestUsize,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MemConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub addr: GuestAddr,
     pub size: usize,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct FixedConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub addr: GuestAddr,
     pub val: GuestUsize,
 }
@@ -72,42 +132,165 @@ pub struct InputConfig {
     pub initial: Vec<PathBuf>,
     pub mem: Vec<MemConfig>,
     pub fixed: Vec<FixedConfig>,
+    #[serde(default)]
+    pub checksums: Vec<ChecksumConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Fletcher32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FlashRange {
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub begin: GuestAddr,
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub end: GuestAddr,
+}
+
+/// A checksum recomputed over the flash contents before every execution
+#[derive(Deserialize, Debug)]
+pub struct ChecksumConfig {
+    pub algorithm: ChecksumAlgorithm,
+    /// Flash address of the 32-bit checksum field
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub field: GuestAddr,
+    /// Checksummed flash range. Without it, the range is taken from the
+    /// header and entry count of the directory whose checksum is `field`
+    #[serde(default)]
+    pub data: Option<FlashRange>,
 }
 
 impl InputConfig {
     pub fn total_size(&self) -> usize {
         self.mem.iter().fold(0, |counter, e| counter + e.size)
     }
+
+    /// Flash writes for an input: the input split over `mem` followed by the `fixed` values
+    pub fn flash_writes(&self, input: &[u8]) -> Vec<(GuestAddr, Vec<u8>)> {
+        let mut target_buf = input;
+        if target_buf.len() > self.total_size() {
+            target_buf = &target_buf[..self.total_size()];
+        }
+        let mut buffer = vec![0; self.total_size()];
+        buffer[..target_buf.len()].copy_from_slice(target_buf);
+        let mut buffer = buffer.as_slice();
+
+        let mut writes = Vec::new();
+        for mem in self.mem.iter() {
+            writes.push((mem.addr, buffer[..mem.size].to_vec()));
+            buffer = &buffer[mem.size..];
+        }
+        for &FixedConfig { addr, val: value } in self.fixed.iter() {
+            writes.push((addr, value.to_ne_bytes().to_vec()));
+        }
+        writes
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct HarnessConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub start: GuestAddr,
+    #[serde(deserialize_with = "deserialize_addrs")]
     pub sinks: Vec<GuestAddr>,
+    /// Wall-clock limit of one execution in milliseconds
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Executed basic blocks after which an execution is stopped as a hang
+    #[serde(default)]
+    pub max_blocks: Option<u64>,
+}
+
+fn default_timeout_ms() -> u64 {
+    15000
+}
+
+/// Delivering the input as x86-to-PSP mailbox commands instead of writing it to the flash
+#[derive(Deserialize, Debug, Clone)]
+pub struct MailboxConfig {
+    /// C2P mailbox registers in the PSP address space: the command register
+    /// followed by the 64-bit buffer pointer
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub regs: GuestAddr,
+    /// Where the workloop waits for the next command
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub idle: GuestAddr,
+    /// Command buffer in the PSP address space, the payload of a command is written there
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_opt_addr")]
+    pub buffer: Option<GuestAddr>,
+    /// x86 address of the command buffer, used by the initial inputs
+    #[serde(default)]
+    pub buffer_ptr: u64,
+    /// Commands delivered per execution
+    #[serde(default = "default_max_commands")]
+    pub max_commands: usize,
+    /// Payload bytes per command
+    #[serde(default = "default_max_payload")]
+    pub max_payload: usize,
+    /// Command IDs of the initial inputs, one sequence per input
+    #[serde(default)]
+    pub initial: Vec<Vec<u8>>,
+}
+
+fn default_max_commands() -> usize {
+    4
+}
+
+fn default_max_payload() -> usize {
+    0x100
 }
 #[derive(Deserialize, Debug)]
 pub struct NoExecConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub begin: GuestAddr,
+    #[serde(deserialize_with = "deserialize_addr")]
     pub end: GuestAddr,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RegionWithHoles {
+    #[serde(deserialize_with = "deserialize_addr")]
     pub begin: GuestAddr,
+    #[serde(deserialize_with = "deserialize_addr")]
     pub end: GuestAddr,
     #[serde(default)]
+    #[serde(deserialize_with = "deserialize_addrs")]
     pub holes: Vec<GuestAddr>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct CrashConfig {
+    #[serde(deserialize_with = "deserialize_addrs")]
     pub breakpoints: Vec<GuestAddr>,
     pub mmap: MmapConfig,
+    #[serde(default)]
+    pub buckets: BucketConfig,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BucketConfig {
+    /// Number of solutions kept per crash bucket
+    pub max_solutions: u64,
+    /// Number of return addresses that are part of the crash signature
+    pub stack_depth: usize,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            max_solutions: 1,
+            stack_depth: 4,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct MmapConfig {
     pub no_exec: Vec<NoExecConfig>,
+    #[serde(deserialize_with = "deserialize_addr")]
     pub flash_read_fn: GuestAddr,
     pub no_write_flash_fn: Vec<RegionWithHoles>,
     pub no_write_hooks: Vec<RegionWithHoles>,
@@ -119,31 +302,394 @@ pub struct SnapshotConfig {
     pub on_crash: ResetLevel,
     pub periodically: ResetLevel,
     pub period: usize,
+    /// Compare the state after every `check_period`th reset with the snapshot (debugging)
+    #[serde(default)]
+    pub check_period: Option<usize>,
+    /// Store the state at `harness.start` in the run directory, later clients load it
+    /// instead of booting again
+    #[serde(default)]
+    pub persist: bool,
+    /// Named points after `harness.start` the fuzzing can start from
+    #[serde(default)]
+    pub points: Vec<SnapshotPoint>,
+    /// Name of the point to start the executions from instead of `harness.start`
+    #[serde(default)]
+    pub start_point: Option<String>,
+}
+
+/// A point in the execution to take a snapshot at
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnapshotPoint {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub addr: GuestAddr,
+}
+
+impl SnapshotConfig {
+    /// The point selected by `start_point`
+    pub fn start_point(&self) -> Option<&SnapshotPoint> {
+        let name = self.start_point.as_ref()?;
+        self.points.iter().find(|point| &point.name == name)
+    }
+
+    /// Whether `level` is used by any of the snapshot triggers
+    pub fn uses(&self, level: ResetLevel) -> bool {
+        [self.default, self.on_crash, self.periodically].contains(&level)
+    }
+}
+
+/// Corpus scheduling: a plain queue or one of the AFL++ power schedules
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerConfig {
+    #[default]
+    Queue,
+    Explore,
+    Exploit,
+    Fast,
+    Coe,
+    Lin,
+    Quad,
+}
+
+impl SchedulerConfig {
+    pub fn is_power_schedule(&self) -> bool {
+        *self != SchedulerConfig::Queue
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MutatorConfig {
+    #[default]
+    Havoc,
+    MOpt,
+}
+
+/// Stages running in addition to the mutational stage
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct StagesConfig {
+    /// Measures the execution time and stability of new test-cases
+    #[serde(default)]
+    pub calibration: bool,
+    /// Generalizes new test-cases and mutates them with the Grimoire mutators
+    #[serde(default)]
+    pub generalization: bool,
+    /// Traces comparisons and replaces their operands in the input
+    #[serde(default)]
+    pub tracing: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FuzzerConfig {
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
+    pub mutator: MutatorConfig,
+    /// Adds the PSP directory mutations to the mutator
+    #[serde(default = "default_true")]
+    pub psp_mutations: bool,
+    /// The PSP mutations adding or removing entries update the entry count
+    #[serde(default = "default_true")]
+    pub psp_fix_count: bool,
+    /// Dictionary file for the token mutations
+    #[serde(default)]
+    pub tokens: Option<PathBuf>,
+    #[serde(default)]
+    pub stages: StagesConfig,
+}
+
+impl FuzzerConfig {
+    /// Whether the calibration stage runs, the power schedules need it
+    pub fn calibration(&self) -> bool {
+        self.stages.calibration || self.scheduler.is_power_schedule()
+    }
+
+    /// The section as used by the run, with the calibration stage a power schedule enables
+    pub fn effective(&self) -> Value {
+        let mut section = serde_yaml::to_value(self).unwrap();
+        section["stages"]["calibration"] = Value::Bool(self.calibration());
+        section
+    }
+}
+
+impl Default for FuzzerConfig {
+    fn default() -> Self {
+        Self {
+            scheduler: SchedulerConfig::default(),
+            mutator: MutatorConfig::default(),
+            psp_mutations: true,
+            psp_fix_count: true,
+            tokens: None,
+            stages: StagesConfig::default(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct YAMLConfig {
     pub qemu: QemuConf,
     pub flash: FlashConfig,
+    pub memory: MemoryConfig,
     pub input: InputConfig,
     pub harness: HarnessConfig,
     pub tunnels: TunnelConfig,
     pub crashes: CrashConfig,
     pub snapshot: SnapshotConfig,
+    #[serde(default)]
+    pub fuzzer: FuzzerConfig,
+    /// Mailbox harness mode, the input is a sequence of mailbox commands
+    #[serde(default)]
+    pub mailbox: Option<MailboxConfig>,
 }
 
-pub fn init_global_conf(config_path: &Path, num_cores: u32, run_dir: PathBuf) {
-    let yaml = YAMLConfig::new(config_path);
+pub fn init_global_conf(
+    config_path: &Path,
+    num_cores: u32,
+    run_dir: PathBuf,
+    overrides: Vec<ConfigOverride>,
+) {
+    let yaml = YAMLConfig::load_with(config_path, &overrides)
+        .unwrap_or_else(|diagnostic| panic!("{diagnostic}"));
 
     CONF.set(RunConfig {
         yaml_config: yaml,
         num_cores,
         run_dir,
         config_path: config_path.to_owned(),
+        overrides,
     })
     .unwrap();
 }
 
+/// Lists that are appended to the ones of the base config instead of replacing them
+const MERGED_LISTS: [&[&str]; 3] = [&["tunnels"], &["harness", "sinks"], &["input", "fixed"]];
+
+/// Merges `overlay` into `base`: mappings key by key, scalars and other lists are replaced
+fn merge_yaml(base: &mut Value, overlay: Value, path: &mut Vec<String>) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                path.push(key.as_str().unwrap_or_default().to_string());
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_yaml(base_value, value, path),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+                path.pop();
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay))
+            if MERGED_LISTS
+                .iter()
+                .any(|list| list.iter().copied().eq(path.iter().map(String::as_str))) =>
+        {
+            base.extend(overlay)
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Reads a config and merges it over the config it `extends`, whose path is
+/// relative to the extending file
+fn read_extended(config_file: &Path, chain: &mut Vec<PathBuf>) -> Result<Value, Diagnostic> {
+    let yaml = fs::read_to_string(config_file).map_err(|e| Diagnostic::parse(config_file, e))?;
+    let mut value: Value =
+        serde_yaml::from_str(&yaml).map_err(|e| Diagnostic::parse(config_file, e))?;
+    let Some(extends) = value.as_mapping_mut().and_then(|m| m.remove("extends")) else {
+        return Ok(value);
+    };
+    let Some(base_file) = extends.as_str() else {
+        return Err(Diagnostic::parse(
+            config_file,
+            "`extends` has to be a file path",
+        ));
+    };
+    let base_file = config_file.parent().unwrap().join(base_file);
+    let canonical = base_file
+        .canonicalize()
+        .map_err(|e| Diagnostic::parse(&base_file, e))?;
+    if chain.contains(&canonical) {
+        return Err(Diagnostic::parse(config_file, "`extends` forms a cycle"));
+    }
+    chain.push(canonical);
+    let mut base = read_extended(&base_file, chain)?;
+    chain.pop();
+    merge_yaml(&mut base, value, &mut Vec::new());
+    Ok(base)
+}
+
+/// Serializes a config document. Empty values are written as in the shipped configs
+/// (`breakpoints:` instead of `breakpoints: null`), as only these read back as empty lists.
+fn to_yaml(value: &Value) -> String {
+    let mut yaml = String::new();
+    for line in serde_yaml::to_string(value).unwrap().lines() {
+        let line = match line.strip_suffix(" null") {
+            Some(key) if key.ends_with(':') || key.trim_start() == "-" => key,
+            _ => line,
+        };
+        yaml.push_str(line);
+        yaml.push('\n');
+    }
+    yaml
+}
+
+/// The config with all `extends` resolved. A config without `extends` is kept as it is.
+pub fn resolved_yaml(config_file: &Path) -> Result<String, Diagnostic> {
+    let yaml = fs::read_to_string(config_file).map_err(|e| Diagnostic::parse(config_file, e))?;
+    let value: Value =
+        serde_yaml::from_str(&yaml).map_err(|e| Diagnostic::parse(config_file, e))?;
+    if value.get("extends").is_none() {
+        return Ok(yaml);
+    }
+    let mut chain = vec![config_file
+        .canonicalize()
+        .map_err(|e| Diagnostic::parse(config_file, e))?];
+    let value = read_extended(config_file, &mut chain)?;
+    Ok(format!(
+        "# Resolved from {}\n{}",
+        config_file.display(),
+        to_yaml(&value)
+    ))
+}
+
+/// A `--set key.path=value` override of a single config value
+#[derive(Debug, Clone)]
+pub struct ConfigOverride {
+    /// Keys from the top of the document, numbers index into lists
+    pub path: Vec<String>,
+    pub value: Value,
+}
+
+impl FromStr for ConfigOverride {
+    type Err = String;
+
+    /// The value is parsed as YAML, so `0x10`, `Lazy` or `[1, 2]` work as in the file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("`{s}` is not of the form key.path=value"))?;
+        let path: Vec<String> = key.trim().split('.').map(str::to_string).collect();
+        if path.iter().any(String::is_empty) {
+            return Err(format!("`{key}` is no valid key path"));
+        }
+        let value =
+            serde_yaml::from_str(value).map_err(|e| format!("Invalid value `{value}`: {e}"))?;
+        Ok(Self { path, value })
+    }
+}
+
+impl ConfigOverride {
+    pub fn key(&self) -> String {
+        self.path.join(".")
+    }
+
+    /// Sets the value in `doc`, creating missing mapping keys on the way
+    fn apply(&self, doc: &mut Value) -> Result<(), String> {
+        let mut target = doc;
+        for key in &self.path {
+            if target.is_null() {
+                *target = Value::Mapping(Mapping::new());
+            }
+            target = match target {
+                Value::Mapping(map) => map.entry(Value::String(key.clone())).or_insert(Value::Null),
+                Value::Sequence(list) => {
+                    let len = list.len();
+                    key.parse()
+                        .ok()
+                        .and_then(|idx: usize| list.get_mut(idx))
+                        .ok_or_else(|| {
+                            format!("{}: `{key}` is no index of a list of {len}", self.key())
+                        })?
+                }
+                _ => return Err(format!("{}: `{key}` is inside a plain value", self.key())),
+            };
+        }
+        *target = self.value.clone();
+        Ok(())
+    }
+}
+
+/// Applies `overrides` to a YAML document. Without overrides the document is kept as it is.
+pub fn apply_overrides(yaml: &str, overrides: &[ConfigOverride]) -> Result<String, String> {
+    set_values(yaml, overrides, "Overridden on the command line")
+}
+
+fn set_values(yaml: &str, values: &[ConfigOverride], comment: &str) -> Result<String, String> {
+    if values.is_empty() {
+        return Ok(yaml.to_string());
+    }
+    let mut doc: Value = serde_yaml::from_str(yaml).map_err(|e| e.to_string())?;
+    for value in values {
+        value.apply(&mut doc)?;
+    }
+    let keys: Vec<String> = values.iter().map(ConfigOverride::key).collect();
+    Ok(format!(
+        "# {comment}: {}\n{}",
+        keys.join(", "),
+        to_yaml(&doc)
+    ))
+}
+
+/// Fills in the `flash` and `memory` keys left out of the config with the defaults of `qemu.zen`
+fn apply_zen_defaults(yaml: &str) -> Result<String, String> {
+    let doc: Value = serde_yaml::from_str(yaml).map_err(|e| e.to_string())?;
+    // An invalid or missing generation is reported by the deserialization
+    let Some(Ok(zen)) = doc
+        .get("qemu")
+        .and_then(|qemu| qemu.get("zen"))
+        .map(|zen| serde_yaml::from_value::<ZenVersion>(zen.clone()))
+    else {
+        return Ok(yaml.to_string());
+    };
+    let defaults: Vec<ConfigOverride> = zen
+        .defaults()
+        .into_iter()
+        .filter(|(section, key, _)| {
+            doc.get(section)
+                .and_then(|section| section.get(key))
+                .is_none()
+        })
+        .map(|(section, key, value)| ConfigOverride {
+            path: vec![section.to_string(), key.to_string()],
+            value: Value::from(value),
+        })
+        .collect();
+    set_values(yaml, &defaults, "Defaults of the Zen generation")
+}
+
+impl RunConfig {
+    /// Writes the effective YAML config to `path`: `extends` resolved, the `--set`
+    /// overrides applied and the `fuzzer` section written out in full
+    pub fn write_config(&self, path: &Path) {
+        let yaml = resolved_yaml(&self.config_path)
+            .and_then(|yaml| {
+                apply_overrides(&yaml, &self.overrides)
+                    .map_err(|e| Diagnostic::parse(&self.config_path, e))
+            })
+            .unwrap_or_else(|d| panic!("{d}"));
+        let section = ConfigOverride {
+            path: vec!["fuzzer".to_string()],
+            value: self.yaml_config.fuzzer.effective(),
+        };
+        let yaml = set_values(&yaml, &[section], "Fuzzer settings used for this run").unwrap();
+        fs::write(path, yaml).unwrap();
+    }
+}
+
+/// Resolves a relative path against `PROJECT_DIR` like the QEMU arguments,
+/// or against the directory of the config file if it is not set
+fn project_path(path: &Path, config_dir: &Path) -> PathBuf {
+    match env::var("PROJECT_DIR") {
+        _ if path.is_absolute() => path.to_path_buf(),
+        Ok(project_dir) => Path::new(&project_dir).join(path),
+        Err(_) => config_dir.join(path),
+    }
+}
+
 pub fn borrow_global_conf() -> Option<&'static YAMLConfig> {
     get_run_conf().map(|e| &e.yaml_config)
 }
@@ -151,14 +697,50 @@ pub fn get_run_conf() -> Option<&'static RunConfig> {
     CONF.get()
 }
 impl YAMLConfig {
-    fn new(config_file: &Path) -> Self {
-        let file = File::options()
-            .read(true)
-            .write(false)
-            .open(config_file)
-            .expect("Unable to open yaml config file");
+    /// Reads the config, reporting where the YAML does not match the config structure
+    pub fn load(config_file: &Path) -> Result<Self, Diagnostic> {
+        Self::load_with(config_file, &[])
+    }
+
+    /// Reads the config with `--set` overrides applied on top
+    pub fn load_with(config_file: &Path, overrides: &[ConfigOverride]) -> Result<Self, Diagnostic> {
+        let yaml = apply_overrides(&resolved_yaml(config_file)?, overrides)
+            .and_then(|yaml| apply_zen_defaults(&yaml))
+            .map_err(|e| Diagnostic::parse(config_file, e))?;
+        let config_dir = config_file.parent().unwrap_or(Path::new("."));
+        let symbols = match Self::symbols_path(&yaml) {
+            Some(path) => Self::load_symbols(&path, config_dir)
+                .map_err(|e| Diagnostic::parse(config_file, e))?,
+            None => SymbolMap::default(),
+        };
+
+        let mut config: Self = with_symbols(symbols, || serde_yaml::from_str(&yaml))
+            .map_err(|e| Diagnostic::parse(config_file, e))?;
+        config.qemu.symbols = config
+            .qemu
+            .symbols
+            .map(|path| project_path(&path, config_dir));
+        Ok(config)
+    }
+
+    /// The symbol file named in `qemu.symbols`, read before the rest of the config
+    fn symbols_path(yaml: &str) -> Option<PathBuf> {
+        #[derive(Deserialize)]
+        struct Symbols {
+            #[serde(default)]
+            symbols: Option<PathBuf>,
+        }
+        #[derive(Deserialize)]
+        struct Qemu {
+            qemu: Symbols,
+        }
+        // Otherwise the full deserialization reports the problem
+        serde_yaml::from_str::<Qemu>(yaml).ok()?.qemu.symbols
+    }
 
-        serde_yaml::from_reader(file).unwrap()
+    /// Reads a symbol file, relative paths are resolved by `project_path`
+    pub fn load_symbols(path: &Path, config_dir: &Path) -> Result<SymbolMap, String> {
+        SymbolMap::from_file(&project_path(path, config_dir))
     }
 }
 
@@ -170,38 +752,266 @@ mod tests {
     fn read_zen1() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/ryzen_zen1_desktop_parse_asp_flash.yaml"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zen1_desktop_parse_asp_flash.yaml"))
+            .unwrap();
     }
 
     #[test]
     fn read_zen2() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/ryzen_zen2_desktop_parse_asp_flash.yaml"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zen2_desktop_parse_asp_flash.yaml"))
+            .unwrap();
     }
     #[test]
     fn read_zen3() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/ryzen_zen3_desktop_parse_asp_flash.yaml"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zen3_desktop_parse_asp_flash.yaml"))
+            .unwrap();
     }
     #[test]
     fn read_zentesla() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/ryzen_zentesla_parse_asp_flash.yaml"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zentesla_parse_asp_flash.yaml")).unwrap();
     }
     #[test]
     fn read_zenplus() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/ryzen_zen+_desktop_parse_asp_flash.yaml"));
+        YAMLConfig::load(&d.join("../amd_sp/yaml/ryzen_zen+_desktop_parse_asp_flash.yaml"))
+            .unwrap();
+    }
+
+    #[test]
+    fn read_zen4() {
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let dir = std::env::temp_dir().join(format!("zen4-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let symbols = dir.join("zen4.nm");
+        fs::write(
+            &symbols,
+            "ffff05c0 t post_status_wfi_loop\n\
+             ffff2184 t load_pubkey_flash_read_ret\n\
+             ffff24b8 T call_off_chip\n\
+             ffff2bf8 T parse_psp_flash\n\
+             ffff731c T on_chip_bl_flash_read\n\
+             0005f410 b rom_svc_pubkey\n\
+             0005fbb0 b rom_svc_pubkey_hash\n",
+        )
+        .unwrap();
+        let conf = YAMLConfig::load_with(
+            &d.join("../amd_sp/yaml/ryzen_zen4_desktop_parse_asp_flash.yaml"),
+            &[format!("qemu.symbols={}", symbols.display())
+                .parse()
+                .unwrap()],
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(conf.qemu.zen.get_qemu_machine_name(), "amd-psp-zen4");
+        assert_eq!(conf.flash.start_smn, 0x44000000);
+        assert_eq!(conf.flash.size, 0x01000000);
+        assert_eq!(conf.memory, ZenVersion::Zen4.memory());
+        assert_eq!(conf.harness.start, 0xffff2bf8);
+        assert_eq!(conf.harness.sinks, vec![0xffff05c0, 0xffff24b8]);
+        let pubkey = &conf.crashes.mmap.no_write_flash_fn[1];
+        assert_eq!((pubkey.begin, pubkey.end), (0x5f410, 0x5f850));
+        assert_eq!(pubkey.holes, vec![0xffff2184]);
+    }
+
+    #[test]
+    fn read_symbolic_addresses() {
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let yaml =
+            fs::read_to_string(d.join("../amd_sp/yaml/ryzen_zen2_desktop_parse_asp_flash.yaml"))
+                .unwrap();
+        let dir = std::env::temp_dir().join(format!("symbols-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let symbols = dir.join("zen2.nm");
+        fs::write(&symbols, "ffff2c00 T main\n").unwrap();
+        let start = yaml
+            .lines()
+            .find(|line| line.trim_start().starts_with("start: 0xffff"))
+            .unwrap();
+        let common = d.join("../amd_sp/yaml/ryzen_common.yaml");
+        let yaml = yaml
+            .replace(start, "  start: main+0x10")
+            .replace(
+                "\"ryzen_common.yaml\"",
+                &format!("\"{}\"", common.display()),
+            )
+            .replace(
+                "  zen: \"Zen2\"",
+                &format!("  zen: \"Zen2\"\n  symbols: \"{}\"", symbols.display()),
+            );
+        let config = dir.join("zen2.yaml");
+        fs::write(&config, yaml).unwrap();
+        let conf = YAMLConfig::load(&config).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(conf.harness.start, 0xffff2c10);
+        assert_eq!(conf.qemu.symbols, Some(symbols));
+    }
+
+    #[test]
+    fn extends_merges_lists() {
+        let dir = std::env::temp_dir().join(format!("extends-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("base.yaml"),
+            "harness:\n  start: 0x10\n  sinks: [0x20]\nsnapshot:\n  period: 1\n  default: Lazy\nlist: [1]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("child.yaml"),
+            "extends: base.yaml\nharness:\n  sinks: [0x30]\nsnapshot:\n  period: 2\nlist: [2]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("cycle.yaml"), "extends: cycle.yaml\n").unwrap();
+        let resolved: Value =
+            serde_yaml::from_str(&resolved_yaml(&dir.join("child.yaml")).unwrap()).unwrap();
+        let cycle = resolved_yaml(&dir.join("cycle.yaml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let expected: Value = serde_yaml::from_str(
+            "harness:\n  start: 0x10\n  sinks: [0x20, 0x30]\nsnapshot:\n  period: 2\n  default: Lazy\nlist: [2]\n",
+        )
+        .unwrap();
+        assert_eq!(resolved, expected);
+        assert!(cycle.unwrap_err().message.contains("cycle"));
+    }
+
+    #[test]
+    fn empty_values_stay_empty() {
+        let doc: Value =
+            serde_yaml::from_str("crashes:\n  breakpoints:\nlist:\n- \n- 1\nname: 'null'\n")
+                .unwrap();
+        assert_eq!(
+            to_yaml(&doc),
+            "crashes:\n  breakpoints:\nlist:\n-\n- 1\nname: 'null'\n"
+        );
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let config = d.join("../amd_sp/yaml/ryzen_zen2_desktop_parse_asp_flash.yaml");
+        let overrides: Vec<ConfigOverride> = [
+            "snapshot.default=Lazy",
+            "snapshot.period=0x10",
+            "harness.sinks.1=0xffff0000",
+            "input.mem.0.size=8",
+            "fuzzer.scheduler=Fast",
+            "memory.lazy_size=0x2000",
+            "snapshot.start_point=copy_pubkey",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let conf = YAMLConfig::load_with(&config, &overrides).unwrap();
+        assert!(matches!(conf.snapshot.default, ResetLevel::Lazy));
+        assert_eq!(conf.snapshot.period, 0x10);
+        assert_eq!(conf.harness.sinks[1], 0xffff0000);
+        assert_eq!(conf.input.mem[0].size, 8);
+        assert_eq!(conf.fuzzer.scheduler, SchedulerConfig::Fast);
+        assert_eq!(conf.memory.lazy_size, 0x2000);
+        assert_eq!(conf.memory.sram_size, 0x5_0000);
+        assert_eq!(conf.snapshot.start_point().unwrap().addr, 0xffff2140);
+
+        assert!("snapshot.default".parse::<ConfigOverride>().is_err());
+        assert!("snapshot..default=Lazy".parse::<ConfigOverride>().is_err());
+        let out_of_range = "harness.sinks.5=0".parse().unwrap();
+        assert!(YAMLConfig::load_with(&config, &[out_of_range]).is_err());
+        let into_scalar = "snapshot.period.x=0".parse().unwrap();
+        assert!(YAMLConfig::load_with(&config, &[into_scalar]).is_err());
+    }
+
+    #[test]
+    fn input_flash_writes() {
+        let text = "
+initial:
+mem:
+  - addr: 0x10
+    size: 2
+  - addr: 0x20
+    size: 2
+fixed:
+  - addr: 0x30
+    val: 0x11223344
+";
+        let input: InputConfig = serde_yaml::from_str(text).unwrap();
+        assert_eq!(
+            input.flash_writes(&[1, 2, 3]),
+            vec![
+                (0x10, vec![1, 2]),
+                (0x20, vec![3, 0]),
+                (0x30, 0x11223344u32.to_ne_bytes().to_vec())
+            ]
+        );
+        assert!(input.checksums.is_empty());
+    }
+
+    #[test]
+    fn input_checksums() {
+        let text = "
+initial:
+mem:
+  - addr: 0x10
+    size: 2
+fixed:
+checksums:
+  - algorithm: Fletcher32
+    field: 0xc0004
+  - algorithm: Fletcher32
+    field: 0x400
+    data:
+      begin: 0x404
+      end: 0x500
+";
+        let input: InputConfig = serde_yaml::from_str(text).unwrap();
+        assert_eq!(input.checksums.len(), 2);
+        assert!(input.checksums[0].data.is_none());
+        assert_eq!(input.checksums[1].data.as_ref().unwrap().end, 0x500);
+    }
+
+    #[test]
+    fn fuzzer_defaults() {
+        let conf: FuzzerConfig = serde_yaml::from_str("scheduler: Fast").unwrap();
+        assert!(conf.scheduler.is_power_schedule());
+        assert_eq!(conf.mutator, MutatorConfig::Havoc);
+        assert!(conf.psp_mutations);
+        assert!(conf.psp_fix_count);
+        assert!(!conf.stages.calibration);
+    }
+
+    #[test]
+    fn effective_fuzzer_section() {
+        let conf: FuzzerConfig = serde_yaml::from_str("scheduler: Fast").unwrap();
+        let yaml = "qemu:\n  zen: Zen2\nfuzzer:\n  scheduler: Fast\n# Column 0 comment\n\n  mutator: MOpt\nsnapshot:\n  period: 1\n";
+        let section = ConfigOverride {
+            path: vec!["fuzzer".to_string()],
+            value: conf.effective(),
+        };
+        let doc: Value =
+            serde_yaml::from_str(&set_values(yaml, &[section], "Fuzzer").unwrap()).unwrap();
+        assert_eq!(doc["qemu"]["zen"], Value::from("Zen2"));
+        assert_eq!(doc["snapshot"]["period"], Value::from(1));
+        assert_eq!(doc["fuzzer"]["scheduler"], Value::from("Fast"));
+        assert_eq!(doc["fuzzer"]["mutator"], Value::from("Havoc"));
+        // Enabled by the power schedule
+        assert_eq!(doc["fuzzer"]["stages"]["calibration"], Value::Bool(true));
     }
 
     #[test]
     fn read_mailbox() {
         let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         println!("CARGO PATH IS {}", d.display());
-        YAMLConfig::new(&d.join("../amd_sp/yaml/mailbox.yaml"));
+        let conf = YAMLConfig::load(&d.join("../amd_sp/yaml/mailbox.yaml")).unwrap();
+        let mailbox = conf.mailbox.unwrap();
+        assert_eq!(mailbox.regs, 0x0301_0570);
+        assert_eq!(mailbox.idle, 0x0210);
+        assert_eq!(mailbox.max_payload, 0);
+        assert_eq!(mailbox.buffer, None);
+        assert_eq!(mailbox.initial, vec![vec![0x02, 0x03], vec![0x05]]);
     }
 }
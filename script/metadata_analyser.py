@@ -1,4 +1,5 @@
 import glob
+import json
 import re
 import os
 from pathlib import Path
@@ -20,11 +21,27 @@ parser.add_argument(
 args = parser.parse_args()
 
 run_dir_path = Path(args.run_dir)
-uninteresting_pc = args.uninteresting_pc
+uninteresting_pc = [int(pc, 16) for pc in args.uninteresting_pc]
 
 hash_re = re.compile("/.([0-9a-f\-]{16,19}).metadata")
-pc_re = re.compile("pc: (0x[0-9a-f]*)")
-lr_re = re.compile("lr: (0x[0-9a-f]*)")
+
+
+def find_metadata(node, keys):
+    """Search an entry with the given keys inside the serialized LibAFL metadata map"""
+    if isinstance(node, dict):
+        if all(key in node for key in keys):
+            return node
+        children = node.values()
+    elif isinstance(node, list):
+        children = node
+    else:
+        return None
+    for child in children:
+        found = find_metadata(child, keys)
+        if found is not None:
+            return found
+    return None
+
 
 solutions_path = os.path.join(run_dir_path, Path("solutions"))
 if not os.path.isdir(solutions_path):
@@ -39,22 +56,39 @@ for metadata_file in glob.glob(os.path.join(solutions_path, ".*.metadata")):
     if hash_re_result:
         hash_val = hash_re_result[1]
         with open(metadata_file, "r") as f:
-            lines = [line.rstrip().replace('"', "").strip() for line in f]
-        for line in lines:
-            pc_out = re.match(pc_re, line)
-            lr_out = re.match(lr_re, line)
-            if pc_out:
-                pc = pc_out[1]
-            if lr_out:
-                lr = lr_out[1]
-        metadata_dict[hash_val] = {"pc": pc, "lr": lr}
+            metadata_json = json.load(f)
+        metadata = find_metadata(metadata_json, ["regs", "exit"])
+        if metadata is None:
+            print(f"No register metadata in {metadata_file}")
+            continue
+        metadata["exception_info"] = find_metadata(
+            metadata_json, ["exception", "fault_pc"]
+        )
+        bucket_info = find_metadata(metadata_json, ["bucket", "signature"])
+        metadata["bucket"] = bucket_info["bucket"] if bucket_info else None
+        metadata_dict[hash_val] = metadata
     else:
         print(f"Could not parse {metadata_file}")
 
 print(f"\nAnalyzed {file_counter} metadata files:\n")
 
 for meta_key, meta_val in metadata_dict.items():
-    if not meta_val["pc"] in uninteresting_pc:
+    if not meta_val["regs"]["pc"] in uninteresting_pc:
+        exit_info = meta_val["exit"] or {}
         print(f"{meta_key}:")
-        print(f"\tpc: {meta_val['pc']}")
-        print(f"\tlr: {meta_val['lr']}")
+        print(f"\tpc: {meta_val['regs']['pc']:#010x}")
+        print(f"\tlr: {meta_val['regs']['lr']:#010x}")
+        print(f"\texit: {exit_info.get('reason')}")
+        if meta_val["bucket"] is not None:
+            print(f"\tbucket: {meta_val['bucket']}")
+        if exit_info.get("hit") is not None:
+            print(f"\thit: {exit_info['hit']:#010x}")
+        if exit_info.get("exception") is not None:
+            print(f"\texception: {exit_info['exception']}")
+        print(f"\treset_level: {exit_info.get('reset_level')}")
+        exception_info = meta_val["exception_info"]
+        if exception_info is not None:
+            print(f"\tbanked lr: {exception_info['lr']:#010x}")
+            for reg in ["fault_pc", "dfar", "dfsr", "ifar", "ifsr"]:
+                if exception_info.get(reg) is not None:
+                    print(f"\t{reg}: {exception_info[reg]:#010x}")
@@ -8,7 +8,7 @@ PORT="1234"
 
 echo "Script to run a flash image in qemu with debugging active"
 echo "Commandline inputs:"
-echo "1:                        Zen generation (Zen1,Zen+,Zen2,Zen3,ZenTesla)"
+echo "1:                        Zen generation (Zen1,Zen+,Zen2,Zen3,Zen4,ZenTesla)"
 echo "2:                        Path to flash image"
 echo "Environment variables:"
 echo "GDB_PORT:                 (Optional) GDB port"
@@ -18,7 +18,7 @@ echo ""
 
 if [ -z $1 ]
 then
-    echo "ERROR: Use commandline input 1 to set the zen gerations (Zen1,Zen+,Zen2,Zen3,ZenTesla)"
+    echo "ERROR: Use commandline input 1 to set the zen gerations (Zen1,Zen+,Zen2,Zen3,Zen4,ZenTesla)"
     exit 1
 fi
 
@@ -44,13 +44,18 @@ case $1 in
     ZEN_GENERATION="zen3"
     ;;
 
+  "Zen4")
+    ROM_BL="../fuzzer/amd_sp/bins/on-chip-bl-Ryzen-Zen4-Desktop"
+    ZEN_GENERATION="zen4"
+    ;;
+
   "ZenTesla")
     ROM_BL="bins/on-chip-bl-Ryzen-ZenTesla"
     ZEN_GENERATION="zentesla"
     ;;
 
   *)
-    echo "ERROR: Use commandline input 1 to set the Zen gerations (Zen1,Zen+,Zen2,Zen3,ZenTesla)"
+    echo "ERROR: Use commandline input 1 to set the Zen gerations (Zen1,Zen+,Zen2,Zen3,Zen4,ZenTesla)"
     exit 1
     ;;
 esac